@@ -1,20 +1,46 @@
 use cosmwasm_std::{
-    entry_point, to_binary, Addr, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdError,
-    StdResult, Uint128, Uint256,
+    entry_point, from_binary, to_binary, Addr, Binary, ContractInfo, CosmosMsg, Decimal256, Deps,
+    DepsMut, Env, Event, MessageInfo, QuerierWrapper, Reply, Response, StdResult, Storage, SubMsg,
+    SubMsgResult, Uint128, Uint256,
 };
-use rebalancer_factory::state::RouteKey;
+use rebalancer_factory::state::{PortfolioLimits, RouteKey};
+use secret_toolkit::permit::{self, Permit, RevokedPermits, TokenPermissions};
 use secret_toolkit::snip20;
+use secret_toolkit::storage::AppendStore;
 
+use crate::error::ContractError;
 use crate::msg::{
     BalanceItem, ExecuteAnswer, ExecuteMsg, Fee, InstantiateMsg, PositionCorrection,
-    PositionDetails, QueryAnswer, QueryMsg, RouterMsg, SwapTokensForExact, UpdateAction,
-    WithdrawAction,
+    PositionDetails, QueryAnswer, QueryMsg, QueryWithPermit, ReceiveMsg, RouterMsg,
+    SwapTokensForExact, UpdateAction, WithdrawAction,
+};
+use crate::state::{
+    AssetSnapshot, Checkpoint, ClaimPool, Config, DepositMode, DepositShare, EmergencyTransfer,
+    NavSnapshot, PendingDeposit, PendingWeights, PendingZapOut, PortfolioConfig, Proposal,
+    ProposalAction, ProposalStatus, QueuedDeposit, VoteOption, CLAIMS, CLAIM_POOLS, CONFIG,
+    DEPOSIT_TOKENS, EMERGENCY_TRANSFERS, FEES, LOCKED_SHARES, NAV_HISTORY, PEAK_NAV,
+    PENDING_DEPOSIT, PENDING_WEIGHTS, PREFIX_REVOKED_PERMITS, PROPOSALS, QUEUED_DEPOSITS,
+    QUEUED_TOTALS, RISK_OFF, SHARE_CHECKPOINTS, STAGED_BASKETS, STAGED_TOTALS, STRAY_TOKENS,
+    TOTAL_CHECKPOINTS, UPDATE_SNAPSHOTS, USER_DEPOSITS, VIEWING_KEY, VOTES, ZAP_OUT,
+};
+use crate::strategy::{apply_risk_off, apply_strategy};
+use rebalancer_factory::events::{add_address_attribute, add_event_address};
+use rebalancer_factory::msg::{
+    factory_execute_msg, query_check_nesting, query_is_allowed, query_prices, query_route,
+    Capacity, ExecuteMsg as FactoryExecuteMsg, Nav, ReceiveMsg as FactoryReceiveMsg, Route,
+    RouteKind, WeightItem, ZapOut,
 };
-use crate::state::{Config, PortfolioConfig, CONFIG, FEES, VIEWING_KEY};
-use rebalancer_factory::msg::{query_prices, query_route};
 
 pub const BLOCK_SIZE: usize = 256;
 pub const NORMALIZATION_FACTOR: u32 = 18;
+pub const DEFAULT_BASKET_TOLERANCE_PERCENT: u128 = 1;
+pub const DEFAULT_WEIGHT_CHANGE_DELAY: u64 = 259_200;
+pub const ZAP_OUT_REPLY_ID: u64 = 1;
+pub const DEPOSIT_REPLY_ID: u64 = 2;
+pub const EMERGENCY_TRANSFER_REPLY_ID: u64 = 3;
+pub const MAX_PAGE_SIZE: u32 = 100;
+// Queued deposits swapped in by one Update, the rest wait for the next
+pub const MAX_QUEUED_DEPOSITS_PER_UPDATE: usize = 20;
 
 #[entry_point]
 pub fn instantiate(
@@ -22,28 +48,91 @@ pub fn instantiate(
     env: Env,
     _info: MessageInfo,
     msg: InstantiateMsg,
-) -> StdResult<Response> {
-    let mut state = Config {
+) -> Result<Response, ContractError> {
+    validate_weights(&msg.portfolio.config)?;
+    let state = Config {
         factory: msg.factory,
-        accepted_deposit_tokens: msg.accepted_deposit_tokens.clone(),
         portfolio: msg.portfolio.clone(),
         admin: msg.admin,
+        address_privacy: msg.address_privacy.unwrap_or_default(),
+        basket_tolerance_percent: msg
+            .basket_tolerance_percent
+            .unwrap_or(DEFAULT_BASKET_TOLERANCE_PERCENT),
+        deposit_mode: msg.deposit_mode.unwrap_or_default(),
+        weight_change_delay: msg
+            .weight_change_delay
+            .unwrap_or(DEFAULT_WEIGHT_CHANGE_DELAY),
+        strategy: msg.strategy.unwrap_or_default(),
+        risk_off: msg.risk_off.clone(),
+        governance: msg.governance.clone(),
+        withdraw_fee: None,
+        tolerance_percent: None,
+        permit_queries: false,
+        limits: PortfolioLimits::default(),
     };
 
     let mut messages = vec![];
 
-    for deposit_token in msg.accepted_deposit_tokens {
+    if let Some(risk_off) = &msg.risk_off {
+        if !msg
+            .portfolio
+            .config
+            .iter()
+            .map(|x| &x.asset)
+            .chain(msg.accepted_deposit_tokens.iter())
+            .any(|x| x.address == risk_off.safe_asset.address)
+        {
+            return Err(ContractError::InvalidConfig(
+                "safe asset must be a portfolio asset or deposit token".to_string(),
+            ));
+        }
+        //TODO remove constants
+        if matches!(risk_off.drawdown_percent, Some(x) if x == 0 || x > 100) {
+            return Err(ContractError::InvalidConfig(
+                "drawdown must be between 1 and 100".to_string(),
+            ));
+        }
+    }
+
+    if let Some(governance) = &msg.governance {
+        //TODO remove constants
+        if governance.quorum_percent > 100 || governance.threshold_percent > 100 {
+            return Err(ContractError::InvalidConfig(
+                "quorum and threshold must not exceed 100".to_string(),
+            ));
+        }
+        // Share tokens are sent here to be locked for voting
+        messages.push(snip20::register_receive_msg(
+            env.contract.code_hash.clone(),
+            None,
+            BLOCK_SIZE,
+            msg.portfolio.snip20.code_hash.clone(),
+            msg.portfolio.snip20.address.clone().into_string(),
+        )?);
+    }
+
+    for deposit_token in msg.accepted_deposit_tokens.clone() {
         messages.push(snip20::register_receive_msg(
             env.contract.code_hash.clone(),
             None,
             BLOCK_SIZE,
             deposit_token.code_hash.clone(),
             deposit_token.address.clone().into_string(),
-        ));
-        state.portfolio.config.push(PortfolioConfig {
-            percent: 0,
-            asset: deposit_token,
-        })
+        )?);
+        if !msg
+            .portfolio
+            .config
+            .iter()
+            .any(|x| x.asset.address == deposit_token.address)
+        {
+            messages.push(snip20::set_viewing_key_msg(
+                msg.viewing_key.clone(),
+                None,
+                BLOCK_SIZE,
+                deposit_token.code_hash,
+                deposit_token.address.into_string(),
+            )?);
+        }
     }
 
     for asset in msg.portfolio.config {
@@ -53,17 +142,24 @@ pub fn instantiate(
             BLOCK_SIZE,
             asset.asset.code_hash,
             asset.asset.address.into_string(),
-        ));
+        )?);
     }
 
     CONFIG.save(deps.storage, &state)?;
     VIEWING_KEY.save(deps.storage, &msg.viewing_key)?;
+    DEPOSIT_TOKENS.save(deps.storage, &msg.accepted_deposit_tokens)?;
+    QUEUED_DEPOSITS.save(deps.storage, &vec![])?;
 
-    Ok(Response::default())
+    Ok(Response::new().add_messages(messages))
 }
 
 #[entry_point]
-pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> StdResult<Response> {
+pub fn execute(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response, ContractError> {
     match msg {
         ExecuteMsg::Update { tolerance_percent } => try_update(deps, env, info, tolerance_percent),
         ExecuteMsg::UpdateKey { viewing_key } => try_update_key(deps, info, viewing_key),
@@ -71,7 +167,35 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> S
             share,
             receiver,
             fee,
-        } => try_withdraw(deps, env, info, share, receiver, fee),
+            zap_out,
+        } => try_withdraw(deps, env, info, share, receiver, fee, zap_out),
+        ExecuteMsg::EmergencyWithdraw { share, receiver } => {
+            try_emergency_withdraw(deps, env, info, share, receiver)
+        }
+        ExecuteMsg::ClaimEmergency { asset } => try_claim_emergency(deps, env, info, asset),
+        ExecuteMsg::DepositBasket {
+            depositor,
+            min_shares,
+        } => try_deposit_basket(deps, env, info, depositor, min_shares),
+        ExecuteMsg::CancelBasket { depositor } => try_cancel_basket(deps, info, depositor),
+        ExecuteMsg::ProposeWeights { config } => try_propose_weights(deps, env, info, config),
+        ExecuteMsg::CancelWeights {} => try_cancel_weights(deps, info),
+        ExecuteMsg::RegisterStrayToken { token } => try_register_stray_token(deps, info, token),
+        ExecuteMsg::RescueTokens {
+            token,
+            amount,
+            sweep,
+        } => try_rescue_tokens(deps, env, info, token, amount, sweep),
+        ExecuteMsg::SetRiskOff { active } => try_set_risk_off(deps, info, active),
+        ExecuteMsg::UnlockShares { amount } => try_unlock_shares(deps, env, info, amount),
+        ExecuteMsg::CreateProposal { action } => try_create_proposal(deps, env, info, action),
+        ExecuteMsg::CastVote { proposal_id, vote } => {
+            try_cast_vote(deps, env, info, proposal_id, vote)
+        }
+        ExecuteMsg::ExecuteProposal { proposal_id } => try_execute_proposal(deps, env, proposal_id),
+        ExecuteMsg::SetPermitQueries { enabled } => try_set_permit_queries(deps, info, enabled),
+        ExecuteMsg::SetLimits { limits } => try_set_limits(deps, info, limits),
+        ExecuteMsg::RevokePermit { permit_name } => try_revoke_permit(deps, info, permit_name),
         ExecuteMsg::Receive {
             sender,
             from,
@@ -89,17 +213,54 @@ pub fn try_withdraw(
     share: Uint128,
     receiver: Addr,
     fee: u128,
-) -> StdResult<Response> {
+    zap_out: Option<ZapOut>,
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
-    if info.sender != config.factory.address || info.sender != config.admin {
-        return Err(StdError::generic_err("Must be factory contract"));
+    // Only the factory burns the shares being paid out
+    if info.sender != config.factory.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
     }
+    let fee = config.withdraw_fee.unwrap_or(fee);
     let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    // Pricing is best-effort here, an in-kind exit never waits on the oracle
+    if let Ok((positions, total_value)) =
+        load_positions(deps.storage, &deps.querier, &env, &config, &viewing_key)
+    {
+        save_snapshot(
+            deps.storage,
+            &deps.querier,
+            &env,
+            &config,
+            &positions,
+            total_value,
+        )?;
+        // Withdrawn value frees up the receiver's user cap
+        release_user_deposit(
+            deps.storage,
+            &receiver,
+            total_value.multiply_ratio(share, Uint128::new(10).pow(NORMALIZATION_FACTOR)),
+        )?;
+    }
+    let held_assets = held_assets(deps.storage, &config)?;
+    if let Some(zap_out) = &zap_out {
+        if !held_assets
+            .iter()
+            .any(|x| x.asset.address == zap_out.asset.address)
+        {
+            return Err(ContractError::InvalidToken(
+                "zap out asset must be a portfolio asset".to_string(),
+            ));
+        }
+    }
 
     let mut messages = vec![];
+    let mut swaps = vec![];
+    let mut zap_out_direct_amount = Uint128::zero();
+    let mut zap_out_fee_amount = Uint128::zero();
     let mut actions = vec![];
+    let mut events = vec![];
 
-    for position in config.portfolio.config.clone() {
+    for position in held_assets {
         let balance = snip20::balance_query(
             deps.querier,
             env.contract.address.clone().into_string(),
@@ -108,37 +269,68 @@ pub fn try_withdraw(
             position.asset.code_hash.clone(),
             position.asset.address.clone().into_string(),
         )?;
-        if balance.amount.is_zero() {
+        let balance = held_balance(deps.storage, &position.asset.address, balance.amount);
+        if balance.is_zero() {
             continue;
         }
-        let full_withdraw_amount = balance
-            .amount
-            .multiply_ratio(share, Uint128::new(10).pow(NORMALIZATION_FACTOR));
+        let full_withdraw_amount =
+            balance.multiply_ratio(share, Uint128::new(10).pow(NORMALIZATION_FACTOR));
         if full_withdraw_amount.is_zero() {
             continue;
         }
         let fee_amount = full_withdraw_amount.multiply_ratio(Uint128::new(fee), Uint128::new(100)); //TODO get rid of constant
         let withdraw_amount = full_withdraw_amount.saturating_sub(fee_amount);
-        messages.append(&mut vec![
-            snip20::transfer_msg(
-                receiver.clone().into_string(),
-                withdraw_amount,
-                None,
-                None,
-                BLOCK_SIZE,
-                position.asset.code_hash.clone(),
-                position.asset.address.clone().into_string(),
-            )?,
-            snip20::transfer_msg(
-                config.admin.clone().into_string(),
-                fee_amount,
-                None,
-                None,
-                BLOCK_SIZE,
-                position.asset.code_hash,
-                position.asset.address.clone().into_string(),
-            )?,
-        ]);
+        match &zap_out {
+            Some(zap_out) if zap_out.asset.address == position.asset.address => {
+                zap_out_direct_amount = withdraw_amount;
+                zap_out_fee_amount = fee_amount;
+            }
+            Some(zap_out) => {
+                let route = query_route(
+                    &deps.querier,
+                    config.factory.clone(),
+                    RouteKey(
+                        position.asset.address.clone(),
+                        zap_out.asset.address.clone(),
+                    ),
+                    viewing_key.clone(),
+                )?;
+                // Output is checked once against min_output after every swap has run
+                swaps.push(route_msg(
+                    route,
+                    &position.asset,
+                    &zap_out.asset,
+                    withdraw_amount,
+                    Uint128::zero(),
+                )?);
+            }
+            None => {
+                messages.push(snip20::transfer_msg(
+                    receiver.clone().into_string(),
+                    withdraw_amount,
+                    None,
+                    None,
+                    BLOCK_SIZE,
+                    position.asset.code_hash.clone(),
+                    position.asset.address.clone().into_string(),
+                )?);
+            }
+        }
+        messages.push(snip20::transfer_msg(
+            config.admin.clone().into_string(),
+            fee_amount,
+            None,
+            None,
+            BLOCK_SIZE,
+            position.asset.code_hash,
+            position.asset.address.clone().into_string(),
+        )?);
+        events.push(
+            Event::new("withdraw_asset")
+                .add_attribute_plaintext("asset", &position.asset.address)
+                .add_attribute_plaintext("amount", withdraw_amount.to_string())
+                .add_attribute_plaintext("fee", fee_amount.to_string()),
+        );
         actions.push(WithdrawAction {
             snip20_addr: position.asset.address.clone(),
             amount: withdraw_amount,
@@ -153,65 +345,242 @@ pub fn try_withdraw(
         )?;
     }
 
-    Ok(Response::new()
+    let response = Response::new()
+        .add_attribute_plaintext("action", "withdraw")
+        .add_attribute_plaintext("share", share.to_string());
+    let response = add_address_attribute(
+        response,
+        "portfolio",
+        &config.portfolio.snip20.address,
+        &config.address_privacy,
+    );
+    let mut response =
+        add_address_attribute(response, "receiver", &receiver, &config.address_privacy);
+
+    let mut submessages = vec![];
+    if let Some(zap_out) = zap_out {
+        response = response.add_attribute_plaintext("zap_out_asset", &zap_out.asset.address);
+        if let Some(last_swap) = swaps.pop() {
+            // The single transfer is made from the reply once the swaps have settled, the fee
+            // transfers run first so the zap asset's fee is already gone from the balance then
+            let balance_before = snip20::balance_query(
+                deps.querier,
+                env.contract.address.clone().into_string(),
+                viewing_key,
+                BLOCK_SIZE,
+                zap_out.asset.code_hash.clone(),
+                zap_out.asset.address.clone().into_string(),
+            )?;
+            ZAP_OUT.save(
+                deps.storage,
+                &PendingZapOut {
+                    receiver,
+                    asset: zap_out.asset,
+                    min_output: zap_out.min_output,
+                    direct_amount: zap_out_direct_amount,
+                    balance_before: balance_before.amount.saturating_sub(zap_out_fee_amount),
+                },
+            )?;
+            submessages = swaps.into_iter().map(SubMsg::new).collect();
+            submessages.push(SubMsg::reply_on_success(last_swap, ZAP_OUT_REPLY_ID));
+        } else {
+            if zap_out_direct_amount < zap_out.min_output {
+                return Err(ContractError::SlippageExceeded(
+                    "zap out returned less than the minimum output".to_string(),
+                ));
+            }
+            messages.push(snip20::transfer_msg(
+                receiver.into_string(),
+                zap_out_direct_amount,
+                None,
+                None,
+                BLOCK_SIZE,
+                zap_out.asset.code_hash,
+                zap_out.asset.address.into_string(),
+            )?);
+        }
+    }
+
+    Ok(response
+        .add_events(events)
         .add_messages(messages)
+        .add_submessages(submessages)
         .set_data(to_binary(&ExecuteAnswer::Withdraw {
             withdraw_assets: actions,
         })?))
 }
 
+pub fn try_finish_zap_out(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let pending = ZAP_OUT.load(deps.storage)?;
+    ZAP_OUT.remove(deps.storage);
+
+    let balance = snip20::balance_query(
+        deps.querier,
+        env.contract.address.into_string(),
+        viewing_key,
+        BLOCK_SIZE,
+        pending.asset.code_hash.clone(),
+        pending.asset.address.clone().into_string(),
+    )?;
+    let output = pending
+        .direct_amount
+        .saturating_add(balance.amount.saturating_sub(pending.balance_before));
+    if output < pending.min_output {
+        return Err(ContractError::SlippageExceeded(
+            "zap out returned less than the minimum output".to_string(),
+        ));
+    }
+    let msg = snip20::transfer_msg(
+        pending.receiver.clone().into_string(),
+        output,
+        None,
+        None,
+        BLOCK_SIZE,
+        pending.asset.code_hash,
+        pending.asset.address.clone().into_string(),
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "zap_out")
+        .add_attribute_plaintext("asset", &pending.asset.address)
+        .add_attribute_plaintext("amount", output.to_string());
+    let response = add_address_attribute(
+        response,
+        "receiver",
+        &pending.receiver,
+        &config.address_privacy,
+    );
+    Ok(response.add_message(msg))
+}
+
 pub fn try_update(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
     tolerance_percent: u128,
-) -> StdResult<Response> {
-    let config = CONFIG.load(deps.storage)?;
-    if info.sender != config.factory.address || info.sender != config.admin {
-        return Err(StdError::generic_err("Must be factory contract"));
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    // Sent by the factory's crank, which pays the keeper from the swaps it reports
+    if info.sender != config.factory.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
     }
+    let tolerance_percent = config.tolerance_percent.unwrap_or(tolerance_percent);
     let viewing_key = VIEWING_KEY.load(deps.storage)?;
 
-    let mut portfolio_total_value = Uint256::zero();
     let mut messages = vec![];
     let mut actions = vec![];
+    let mut events = vec![];
 
-    let mut imbalanced_positions = vec![];
+    let (weights_event, mut weights_messages) =
+        apply_pending_weights(deps.storage, &deps.querier, &env, &mut config)?;
+    events.extend(weights_event);
+    messages.append(&mut weights_messages);
 
-    let temp_config = config.portfolio.config.clone();
-    let price_query_assets = temp_config
-        .into_iter()
-        .map(|x| x.asset.address)
-        .rev()
+    let (mut imbalanced_positions, portfolio_total_value) =
+        load_positions(deps.storage, &deps.querier, &env, &config, &viewing_key)?;
+    // Assets removed by a weight change are dropped once fully sold
+    let exited: Vec<Addr> = imbalanced_positions
+        .iter()
+        .filter(|x| {
+            x.position.percent == 0
+                && x.balance.is_zero()
+                && STAGED_TOTALS
+                    .get(deps.storage, &x.position.asset.address)
+                    .unwrap_or(Uint128::zero())
+                    .is_zero()
+        })
+        .map(|x| x.position.asset.address.clone())
         .collect();
-    let price_query_vec = query_prices(
-        &deps,
-        config.factory.clone(),
-        price_query_assets,
-        viewing_key.clone(),
+    if !exited.is_empty() {
+        config
+            .portfolio
+            .config
+            .retain(|x| x.percent != 0 || !exited.contains(&x.asset.address));
+        CONFIG.save(deps.storage, &config)?;
+    }
+    // Targets come from the strategy, before this update's prices join the history
+    apply_strategy(
+        deps.storage,
+        &deps.querier,
+        &config,
+        &mut imbalanced_positions,
+    )?;
+    let snapshot_index = NAV_HISTORY.get_len(deps.storage)?;
+    let nav = save_snapshot(
+        deps.storage,
+        &deps.querier,
+        &env,
+        &config,
+        &imbalanced_positions,
+        portfolio_total_value,
     )?;
+    UPDATE_SNAPSHOTS.push(deps.storage, &snapshot_index)?;
+    if let Some(event) = check_drawdown(deps.storage, &config, &nav)? {
+        events.push(event);
+    }
+    apply_risk_off(deps.storage, &config, &mut imbalanced_positions)?;
 
-    for asset_position in config.portfolio.config {
-        let price_query = price_query_vec
-            .iter()
-            .find(|&x| x.asset == asset_position.asset.address);
-        if let Some(price) = price_query {
-            let balance = snip20::balance_query(
-                deps.querier,
-                env.contract.address.clone().into_string(),
-                viewing_key.clone(),
-                BLOCK_SIZE,
-                asset_position.asset.code_hash.clone(),
-                asset_position.asset.address.clone().into_string(),
+    // Queued deposits are swapped in first, their reply prices the shares before rebalancing
+    let mut queued = QUEUED_DEPOSITS.load(deps.storage)?;
+    let remaining = queued.split_off(queued.len().min(MAX_QUEUED_DEPOSITS_PER_UPDATE));
+    let mut deposit_submessages = vec![];
+    if !queued.is_empty() {
+        let mut depositors = vec![];
+        let mut deposit_swaps = vec![];
+        for deposit in queued {
+            let value = imbalanced_positions
+                .iter()
+                .find(|x| x.position.asset.address == deposit.asset.address)
+                .map(|x| token_value(deposit.amount, x.price, x.decimals))
+                .ok_or_else(|| ContractError::StalePrice("deposit token".to_string()))?;
+            deposit_swaps.append(&mut swap_in_msgs(
+                &deps.querier,
+                &config,
+                &viewing_key,
+                &imbalanced_positions,
+                &deposit.asset,
+                deposit.amount,
+            )?);
+            let queued_total = QUEUED_TOTALS
+                .get(deps.storage, &deposit.asset.address)
+                .unwrap_or(Uint128::zero());
+            QUEUED_TOTALS.insert(
+                deps.storage,
+                &deposit.asset.address,
+                &queued_total.saturating_sub(deposit.amount),
             )?;
-            let value = Uint256::from_uint128(balance.amount)
-                .saturating_mul(Uint256::from_uint128(price.price));
-            imbalanced_positions.push(PositionDetails {
-                position: asset_position,
+            depositors.push(DepositShare {
+                depositor: deposit.depositor,
                 value,
-                price: price.price,
             });
-            portfolio_total_value = portfolio_total_value.saturating_add(value);
+        }
+        QUEUED_DEPOSITS.save(deps.storage, &remaining)?;
+        match deposit_swaps.pop() {
+            Some(last_swap) => {
+                PENDING_DEPOSIT.save(
+                    deps.storage,
+                    &PendingDeposit {
+                        depositors,
+                        value_before: portfolio_total_value,
+                    },
+                )?;
+                deposit_submessages = deposit_swaps.into_iter().map(SubMsg::new).collect();
+                deposit_submessages.push(SubMsg::reply_on_success(last_swap, DEPOSIT_REPLY_ID));
+            }
+            None => {
+                let added_value = depositors
+                    .iter()
+                    .fold(Uint256::zero(), |acc, x| acc.saturating_add(x.value));
+                messages.append(&mut deposit_mint_msgs(
+                    &deps.querier,
+                    &config,
+                    &depositors,
+                    added_value,
+                    portfolio_total_value,
+                )?);
+            }
         }
     }
 
@@ -222,7 +591,12 @@ pub fn try_update(
         //TODO remove constants
         let target_asset_value =
             portfolio_total_value.multiply_ratio(imbalanced_position.position.percent, 100u128);
-        let tolerance_amount = target_asset_value.multiply_ratio(tolerance_percent, 100u128);
+        let tolerance_amount = tolerance_amount(
+            &imbalanced_position.position,
+            target_asset_value,
+            portfolio_total_value,
+            tolerance_percent,
+        );
         if imbalanced_position
             .value
             .gt(&target_asset_value.saturating_add(tolerance_amount))
@@ -231,6 +605,7 @@ pub fn try_update(
                 position: imbalanced_position.position.clone(),
                 correction: imbalanced_position.value.saturating_sub(target_asset_value),
                 price: imbalanced_position.price,
+                decimals: imbalanced_position.decimals,
             });
         }
         if imbalanced_position
@@ -241,6 +616,7 @@ pub fn try_update(
                 position: imbalanced_position.position,
                 correction: target_asset_value.saturating_sub(imbalanced_position.value),
                 price: imbalanced_position.price,
+                decimals: imbalanced_position.decimals,
             });
         }
     }
@@ -251,30 +627,45 @@ pub fn try_update(
             for under_target_position in &mut under_target {
                 let (sell_amount, expected_return) = {
                     if over_target_correction.eq(&under_target_position.correction) {
-                        let sell_amount = over_target_correction
-                            .checked_div(Uint256::from_uint128(over_target_position.price))?;
-                        let expected_return = under_target_position
-                            .correction
-                            .checked_div(Uint256::from_uint128(under_target_position.price))?;
+                        let sell_amount = token_amount(
+                            over_target_correction,
+                            over_target_position.price,
+                            over_target_position.decimals,
+                        )?;
+                        let expected_return = token_amount(
+                            under_target_position.correction,
+                            under_target_position.price,
+                            under_target_position.decimals,
+                        )?;
                         under_target_position.correction = Uint256::zero();
                         over_target_correction = Uint256::zero();
                         (sell_amount, expected_return)
                     } else if over_target_correction.gt(&under_target_position.correction) {
-                        let sell_amount = (over_target_correction
-                            .saturating_sub(under_target_position.correction))
-                        .checked_div(Uint256::from_uint128(over_target_position.price))?;
-                        let expected_return = under_target_position
-                            .correction
-                            .checked_div(Uint256::from_uint128(under_target_position.price))?;
+                        let sell_amount = token_amount(
+                            over_target_correction.saturating_sub(under_target_position.correction),
+                            over_target_position.price,
+                            over_target_position.decimals,
+                        )?;
+                        let expected_return = token_amount(
+                            under_target_position.correction,
+                            under_target_position.price,
+                            under_target_position.decimals,
+                        )?;
                         over_target_correction =
                             over_target_correction.saturating_sub(under_target_position.correction);
                         under_target_position.correction = Uint256::zero();
                         (sell_amount, expected_return)
                     } else if over_target_correction.lt(&under_target_position.correction) {
-                        let sell_amount = over_target_correction
-                            .checked_div(Uint256::from_uint128(over_target_position.price))?;
-                        let expected_return = over_target_correction
-                            .checked_div(Uint256::from_uint128(under_target_position.price))?;
+                        let sell_amount = token_amount(
+                            over_target_correction,
+                            over_target_position.price,
+                            over_target_position.decimals,
+                        )?;
+                        let expected_return = token_amount(
+                            over_target_correction,
+                            under_target_position.price,
+                            under_target_position.decimals,
+                        )?;
                         over_target_correction = Uint256::zero();
                         under_target_position.correction = under_target_position
                             .correction
@@ -287,7 +678,7 @@ pub fn try_update(
                 if !sell_amount.is_zero() {
                     let min_expected_return = expected_return.multiply_ratio(7u128, 10u128); // TODO
                     let route = query_route(
-                        &deps,
+                        &deps.querier,
                         config.factory.clone(),
                         RouteKey(
                             over_target_position.position.asset.address.clone(),
@@ -295,28 +686,29 @@ pub fn try_update(
                         ),
                         viewing_key.clone(),
                     )?;
-                    let msg = RouterMsg {
-                        swap_tokens_for_exact: SwapTokensForExact {
-                            expected_return: Uint128::try_from(min_expected_return)?,
-                            path: route.route,
-                        },
-                    };
-                    messages.push(snip20::send_msg_with_code_hash(
-                        route.router_contract.address.into_string(),
-                        Some(route.router_contract.code_hash),
+                    messages.push(route_msg(
+                        route,
+                        &over_target_position.position.asset,
+                        &under_target_position.position.asset,
                         Uint128::try_from(sell_amount)?,
-                        Some(to_binary(&msg)?), // TODO
-                        None,
-                        None,
-                        BLOCK_SIZE,
-                        over_target_position.position.asset.code_hash.clone(),
-                        over_target_position
-                            .position
-                            .asset
-                            .address
-                            .clone()
-                            .into_string(),
+                        Uint128::try_from(min_expected_return)?,
                     )?);
+                    events.push(
+                        Event::new("rebalance_swap")
+                            .add_attribute_plaintext(
+                                "asset",
+                                &over_target_position.position.asset.address,
+                            )
+                            .add_attribute_plaintext(
+                                "to_asset",
+                                &under_target_position.position.asset.address,
+                            )
+                            .add_attribute_plaintext("sell_amount", sell_amount.to_string())
+                            .add_attribute_plaintext(
+                                "expected_return",
+                                expected_return.to_string(),
+                            ),
+                    );
                     actions.push(UpdateAction {
                         from_asset: over_target_position.position.asset.clone(),
                         to_asset: under_target_position.position.asset.clone(),
@@ -328,88 +720,2206 @@ pub fn try_update(
         }
     }
 
-    Ok(Response::new()
+    // The factory only rewards keepers for Updates that traded
+    let response = add_address_attribute(
+        Response::new()
+            .add_attribute_plaintext("action", "update")
+            .add_attribute_plaintext(
+                "rebalance_swaps",
+                (actions.len() + deposit_submessages.len()).to_string(),
+            ),
+        "portfolio",
+        &config.portfolio.snip20.address,
+        &config.address_privacy,
+    );
+    Ok(response
+        .add_events(events)
+        .add_submessages(deposit_submessages)
         .add_messages(messages)
         .set_data(to_binary(&ExecuteAnswer::Update { actions })?))
 }
 
-pub fn try_update_key(
-    deps: DepsMut,
-    info: MessageInfo,
-    viewing_key: String,
-) -> StdResult<Response> {
-    let config = CONFIG.load(deps.storage)?;
-    if info.sender != config.factory.address || info.sender != config.admin {
-        return Err(StdError::generic_err("Must be factory contract"));
+// Balances and oracle values of every configured position, staged basket deposits excluded
+pub fn load_positions(
+    storage: &dyn Storage,
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    viewing_key: &str,
+) -> Result<(Vec<PositionDetails>, Uint256), ContractError> {
+    let mut portfolio_total_value = Uint256::zero();
+    let mut positions = vec![];
+
+    let held_assets = held_assets(storage, config)?;
+    let price_query_assets = held_assets
+        .iter()
+        .map(|x| x.asset.address.clone())
+        .rev()
+        .collect();
+    let price_query_vec = query_prices(
+        querier,
+        config.factory.clone(),
+        price_query_assets,
+        viewing_key.to_string(),
+    )?;
+
+    for asset_position in held_assets {
+        let price_query = price_query_vec
+            .iter()
+            .find(|&x| x.asset == asset_position.asset.address);
+        if let Some(price) = price_query {
+            let balance = snip20::balance_query(
+                *querier,
+                env.contract.address.clone().into_string(),
+                viewing_key.to_string(),
+                BLOCK_SIZE,
+                asset_position.asset.code_hash.clone(),
+                asset_position.asset.address.clone().into_string(),
+            )?;
+            let balance = held_balance(storage, &asset_position.asset.address, balance.amount);
+            let value = token_value(balance, price.price, price.decimals);
+            positions.push(PositionDetails {
+                position: asset_position,
+                balance,
+                value,
+                price: price.price,
+                decimals: price.decimals,
+            });
+            portfolio_total_value = portfolio_total_value.saturating_add(value);
+        }
     }
-    // TODO set snip20 viewing key msgs
-    VIEWING_KEY.save(deps.storage, &viewing_key)?;
-    Ok(Response::default())
+
+    Ok((positions, portfolio_total_value))
 }
 
-pub fn try_receive(
-    deps: DepsMut,
-    _env: Env,
-    info: MessageInfo,
-    sender: Addr,
-    _from: Addr,
-    _amount: Uint256,
-    _msg: Option<Binary>,
-) -> StdResult<Response> {
-    let config = CONFIG.load(deps.storage)?;
-    if config
-        .accepted_deposit_tokens
-        .iter()
-        .find(|x| x.address == sender)
-        == None
-    {
-        return Err(StdError::generic_err("Must be a valid deposit token"));
+// Prices are 18 decimal USD for one whole token, so values are in one unit for every asset
+pub fn token_value(amount: Uint128, price: Uint128, decimals: u8) -> Uint256 {
+    Uint256::from_uint128(amount).multiply_ratio(price, Uint128::new(10).pow(decimals as u32))
+}
+
+// Raw amount of a token worth value
+pub fn token_amount(
+    value: Uint256,
+    price: Uint128,
+    decimals: u8,
+) -> Result<Uint256, ContractError> {
+    Ok(value
+        .checked_mul(Uint256::from_uint128(Uint128::new(10).pow(decimals as u32)))?
+        .checked_div(Uint256::from_uint128(price))?)
+}
+
+// Share price of the portfolio, a portfolio with no shares issued starts at 1
+pub fn compute_nav(
+    storage: &dyn Storage,
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    viewing_key: &str,
+) -> Result<Nav, ContractError> {
+    let (_, total_value) = load_positions(storage, querier, env, config, viewing_key)?;
+    nav_for_value(querier, config, total_value)
+}
+
+// Shares minted for value added to a portfolio worth total_value
+pub fn shares_for_value(
+    querier: &QuerierWrapper,
+    config: &Config,
+    value: Uint256,
+    total_value: Uint256,
+) -> Result<Uint128, ContractError> {
+    let token_info = snip20::token_info_query(
+        *querier,
+        BLOCK_SIZE,
+        config.portfolio.snip20.code_hash.clone(),
+        config.portfolio.snip20.address.clone().into_string(),
+    )?;
+    let total_supply = token_info.total_supply.unwrap_or(Uint128::zero());
+    let shares = if total_supply.is_zero() || total_value.is_zero() {
+        value.multiply_ratio(
+            Uint256::from_uint128(Uint128::new(10).pow(token_info.decimals as u32)),
+            Uint256::from_uint128(Uint128::new(10).pow(NORMALIZATION_FACTOR)),
+        )
+    } else {
+        value.multiply_ratio(total_supply, total_value)
+    };
+    Ok(Uint128::try_from(shares)?)
+}
+
+pub fn nav_for_value(
+    querier: &QuerierWrapper,
+    config: &Config,
+    total_value: Uint256,
+) -> Result<Nav, ContractError> {
+    let token_info = snip20::token_info_query(
+        *querier,
+        BLOCK_SIZE,
+        config.portfolio.snip20.code_hash.clone(),
+        config.portfolio.snip20.address.clone().into_string(),
+    )?;
+    let total_supply = token_info.total_supply.unwrap_or(Uint128::zero());
+    let nav_per_share = if total_supply.is_zero() {
+        Uint256::from_uint128(Uint128::new(10).pow(NORMALIZATION_FACTOR))
+    } else {
+        total_value.multiply_ratio(
+            Uint256::from_uint128(Uint128::new(10).pow(token_info.decimals as u32)),
+            total_supply,
+        )
+    };
+    Ok(Nav {
+        total_value,
+        total_supply,
+        nav_per_share,
+    })
+}
+
+pub fn record_snapshot(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    viewing_key: &str,
+) -> Result<(), ContractError> {
+    let (positions, total_value) = load_positions(storage, querier, env, config, viewing_key)?;
+    save_snapshot(storage, querier, env, config, &positions, total_value)?;
+    Ok(())
+}
+
+// Snapshots reflect the portfolio when the action is processed, before its messages execute
+pub fn save_snapshot(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    positions: &[PositionDetails],
+    total_value: Uint256,
+) -> Result<Nav, ContractError> {
+    let nav = nav_for_value(querier, config, total_value)?;
+    if nav.nav_per_share > PEAK_NAV.may_load(storage)?.unwrap_or(Uint256::zero()) {
+        PEAK_NAV.save(storage, &nav.nav_per_share)?;
     }
-    if info.sender != config.factory.address || info.sender != config.admin {
-        return Err(StdError::generic_err("Must be factory contract"));
+    NAV_HISTORY.push(
+        storage,
+        &NavSnapshot {
+            timestamp: env.block.time.seconds(),
+            total_value,
+            nav_per_share: nav.nav_per_share,
+            assets: positions
+                .iter()
+                .map(|x| AssetSnapshot {
+                    asset: x.position.asset.address.clone(),
+                    balance: x.balance,
+                    price: x.price,
+                })
+                .collect(),
+        },
+    )?;
+    Ok(nav)
+}
+
+// Allocation followed by any deposit tokens outside it at 0%
+pub fn held_assets(
+    storage: &dyn Storage,
+    config: &Config,
+) -> Result<Vec<PortfolioConfig>, ContractError> {
+    let mut assets = config.portfolio.config.clone();
+    for deposit_token in DEPOSIT_TOKENS.load(storage)? {
+        if !assets
+            .iter()
+            .any(|x| x.asset.address == deposit_token.address)
+        {
+            assets.push(PortfolioConfig {
+                percent: 0,
+                asset: deposit_token,
+                tolerance: None,
+            });
+        }
     }
-    Ok(Response::default())
+    Ok(assets)
 }
 
-#[entry_point]
-pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::GetConfig {} => to_binary(&get_config(deps)?),
-        QueryMsg::GetFees {} => to_binary(&get_fees(deps)?),
-        QueryMsg::GetBalances {} => to_binary(&get_balances(deps, env)?),
+// value_before is the portfolio without this deposit, queued deposits count towards the TVL cap
+pub fn check_deposit_limits(
+    storage: &dyn Storage,
+    config: &Config,
+    positions: &[PositionDetails],
+    depositor: &Addr,
+    value: Uint256,
+    value_before: Uint256,
+) -> Result<(), ContractError> {
+    let limits = &config.limits;
+    if matches!(limits.min_deposit, Some(min) if value < min) {
+        return Err(ContractError::LimitExceeded(
+            "deposit is below the portfolio minimum".to_string(),
+        ));
+    }
+    if let Some(tvl_cap) = limits.tvl_cap {
+        let tvl = value_before
+            .saturating_add(queued_value(storage, positions))
+            .saturating_add(value);
+        if tvl > tvl_cap {
+            return Err(ContractError::LimitExceeded(
+                "deposit would exceed the portfolio TVL cap".to_string(),
+            ));
+        }
+    }
+    if let Some(user_cap) = limits.user_cap {
+        let deposited = USER_DEPOSITS
+            .get(storage, depositor)
+            .unwrap_or(Uint256::zero());
+        if deposited.saturating_add(value) > user_cap {
+            return Err(ContractError::LimitExceeded(
+                "deposit would exceed the per-user cap".to_string(),
+            ));
+        }
     }
+    Ok(())
 }
 
-fn get_config(deps: Deps) -> StdResult<QueryAnswer> {
-    let state = CONFIG.load(deps.storage)?;
-    Ok(QueryAnswer::GetConfig { config: state })
+pub fn queued_value(storage: &dyn Storage, positions: &[PositionDetails]) -> Uint256 {
+    positions.iter().fold(Uint256::zero(), |acc, position| {
+        let queued = QUEUED_TOTALS
+            .get(storage, &position.position.asset.address)
+            .unwrap_or(Uint128::zero());
+        acc.saturating_add(token_value(queued, position.price, position.decimals))
+    })
 }
 
-fn get_balances(deps: Deps, env: Env) -> StdResult<QueryAnswer> {
-    let state = CONFIG.load(deps.storage)?;
+fn record_user_deposit(
+    storage: &mut dyn Storage,
+    depositor: &Addr,
+    value: Uint256,
+) -> StdResult<()> {
+    let deposited = USER_DEPOSITS
+        .get(storage, depositor)
+        .unwrap_or(Uint256::zero());
+    USER_DEPOSITS.insert(storage, depositor, &deposited.saturating_add(value))
+}
+
+fn release_user_deposit(
+    storage: &mut dyn Storage,
+    receiver: &Addr,
+    value: Uint256,
+) -> StdResult<()> {
+    match USER_DEPOSITS.get(storage, receiver) {
+        Some(deposited) if deposited > value => {
+            USER_DEPOSITS.insert(storage, receiver, &(deposited - value))
+        }
+        Some(_) => USER_DEPOSITS.remove(storage, receiver),
+        None => Ok(()),
+    }
+}
+
+// Contract balance minus staged basket deposits and queued deposits, neither has been minted,
+// and minus what is owed to emergency claims, whose shares are already burned
+pub fn held_balance(storage: &dyn Storage, asset: &Addr, balance: Uint128) -> Uint128 {
+    let available = unminted_balance(storage, asset, balance);
+    match CLAIM_POOLS.get(storage, asset) {
+        Some(pool) => available.saturating_sub(
+            available.multiply_ratio(pool.fraction, Uint128::new(10).pow(NORMALIZATION_FACTOR)),
+        ),
+        None => available,
+    }
+}
+
+fn unminted_balance(storage: &dyn Storage, asset: &Addr, balance: Uint128) -> Uint128 {
+    balance
+        .saturating_sub(STAGED_TOTALS.get(storage, asset).unwrap_or(Uint128::zero()))
+        .saturating_sub(QUEUED_TOTALS.get(storage, asset).unwrap_or(Uint128::zero()))
+}
+
+// Sets aside share of what holders own of asset for receiver
+// Claims are a fraction of the balance, so they follow it until the asset can be paid out
+fn record_claim(
+    storage: &mut dyn Storage,
+    receiver: &Addr,
+    asset: &ContractInfo,
+    share: Uint128,
+) -> StdResult<()> {
+    let scale = Uint128::new(10).pow(NORMALIZATION_FACTOR);
+    let mut pool = CLAIM_POOLS
+        .get(storage, &asset.address)
+        .unwrap_or(ClaimPool {
+            asset: asset.clone(),
+            fraction: Uint128::zero(),
+            units: Uint128::zero(),
+        });
+    let added = scale
+        .saturating_sub(pool.fraction)
+        .multiply_ratio(share, scale);
+    if added.is_zero() {
+        return Ok(());
+    }
+    let units = if pool.units.is_zero() || pool.fraction.is_zero() {
+        added
+    } else {
+        pool.units.multiply_ratio(added, pool.fraction)
+    };
+    pool.fraction = pool.fraction.saturating_add(added);
+    pool.units = pool.units.saturating_add(units);
+    CLAIM_POOLS.insert(storage, &asset.address, &pool)?;
+    let claims = CLAIMS.add_suffix(receiver.as_bytes());
+    let claimed = claims
+        .get(storage, &asset.address)
+        .unwrap_or(Uint128::zero());
+    claims.insert(storage, &asset.address, &claimed.saturating_add(units))
+}
+
+pub fn try_claim_emergency(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    asset: Addr,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let claims = CLAIMS.add_suffix(info.sender.as_bytes());
+    let units = claims
+        .get(deps.storage, &asset)
+        .ok_or_else(|| ContractError::NotFound("emergency claim".to_string()))?;
+    let mut pool = CLAIM_POOLS
+        .get(deps.storage, &asset)
+        .ok_or_else(|| ContractError::NotFound("emergency claim".to_string()))?;
+    let balance = snip20::balance_query(
+        deps.querier,
+        env.contract.address.into_string(),
+        VIEWING_KEY.load(deps.storage)?,
+        BLOCK_SIZE,
+        pool.asset.code_hash.clone(),
+        pool.asset.address.clone().into_string(),
+    )?;
+    let available = unminted_balance(deps.storage, &asset, balance.amount);
+    let scale = Uint128::new(10).pow(NORMALIZATION_FACTOR);
+    let reserved = available.multiply_ratio(pool.fraction, scale);
+    let amount = reserved.multiply_ratio(units, pool.units);
+
+    claims.remove(deps.storage, &asset)?;
+    pool.units = pool.units.saturating_sub(units);
+    let remaining = available.saturating_sub(amount);
+    if pool.units.is_zero() || remaining.is_zero() {
+        CLAIM_POOLS.remove(deps.storage, &asset)?;
+    } else {
+        pool.fraction = reserved
+            .saturating_sub(amount)
+            .multiply_ratio(scale, remaining);
+        CLAIM_POOLS.insert(deps.storage, &asset, &pool)?;
+    }
+    let msg = snip20::transfer_msg(
+        info.sender.clone().into_string(),
+        amount,
+        None,
+        None,
+        BLOCK_SIZE,
+        pool.asset.code_hash,
+        pool.asset.address.into_string(),
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "claim_emergency")
+        .add_attribute_plaintext("asset", &asset)
+        .add_attribute_plaintext("amount", amount.to_string());
+    Ok(
+        add_address_attribute(response, "receiver", &info.sender, &config.address_privacy)
+            .add_message(msg),
+    )
+}
+
+// Entries with their own band use the tighter of its absolute and relative limits,
+// everything else falls back to the tolerance passed on Update
+pub fn tolerance_amount(
+    position: &PortfolioConfig,
+    target_value: Uint256,
+    total_value: Uint256,
+    default_tolerance_percent: u128,
+) -> Uint256 {
+    match &position.tolerance {
+        Some(band) => {
+            //TODO remove constants
+            let absolute = total_value.multiply_ratio(band.absolute_percent, 100u128);
+            let relative = target_value.multiply_ratio(band.relative_percent, 100u128);
+            absolute.min(relative)
+        }
+        None => target_value.multiply_ratio(default_tolerance_percent, 100u128),
+    }
+}
+
+// Pays out raw balances with no oracle or router calls, for when the factory is PROTECTED
+// An asset whose balance query or transfer fails is kept as a claim so the rest still goes out
+pub fn try_emergency_withdraw(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    share: Uint128,
+    receiver: Addr,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.factory.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
+    }
     let viewing_key = VIEWING_KEY.load(deps.storage)?;
-    let mut balances = vec![];
-    for position in state.portfolio.config {
-        let balance = snip20::balance_query(
+
+    let mut submessages = vec![];
+    let mut transfers = vec![];
+    let mut events = vec![];
+    for position in held_assets(deps.storage, &config)? {
+        let balance = match snip20::balance_query(
             deps.querier,
             env.contract.address.clone().into_string(),
             viewing_key.clone(),
             BLOCK_SIZE,
-            position.asset.code_hash,
+            position.asset.code_hash.clone(),
             position.asset.address.clone().into_string(),
-        )?;
-        balances.push(BalanceItem {
-            asset: position.asset.address,
-            amount: balance.amount,
-        })
-    }
-    Ok(QueryAnswer::GetBalances { balances })
-}
-
-fn get_fees(deps: Deps) -> StdResult<QueryAnswer> {
-    let state = CONFIG.load(deps.storage)?;
-    let mut fees = vec![];
-    for position in state.portfolio.config {
+        ) {
+            Ok(balance) => held_balance(deps.storage, &position.asset.address, balance.amount),
+            Err(_) => {
+                record_claim(deps.storage, &receiver, &position.asset, share)?;
+                events.push(
+                    Event::new("withdraw_asset_skipped")
+                        .add_attribute_plaintext("asset", &position.asset.address),
+                );
+                continue;
+            }
+        };
+        let amount = balance.multiply_ratio(share, Uint128::new(10).pow(NORMALIZATION_FACTOR));
+        if amount.is_zero() {
+            continue;
+        }
+        submessages.push(SubMsg::reply_always(
+            snip20::transfer_msg(
+                receiver.clone().into_string(),
+                amount,
+                None,
+                None,
+                BLOCK_SIZE,
+                position.asset.code_hash.clone(),
+                position.asset.address.clone().into_string(),
+            )?,
+            EMERGENCY_TRANSFER_REPLY_ID,
+        ));
+        transfers.push(EmergencyTransfer {
+            receiver: receiver.clone(),
+            asset: position.asset.clone(),
+            share,
+        });
+        events.push(
+            Event::new("withdraw_asset")
+                .add_attribute_plaintext("asset", &position.asset.address)
+                .add_attribute_plaintext("amount", amount.to_string()),
+        );
+    }
+
+    EMERGENCY_TRANSFERS.save(deps.storage, &transfers)?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "emergency_withdraw")
+        .add_attribute_plaintext("share", share.to_string());
+    let response = add_address_attribute(
+        response,
+        "portfolio",
+        &config.portfolio.snip20.address,
+        &config.address_privacy,
+    );
+    Ok(
+        add_address_attribute(response, "receiver", &receiver, &config.address_privacy)
+            .add_events(events)
+            .add_submessages(submessages),
+    )
+}
+
+// The failed asset stays in the portfolio as a claim, the rest of the payout goes through
+fn try_finish_emergency_transfer(deps: DepsMut, success: bool) -> Result<Response, ContractError> {
+    let mut transfers = EMERGENCY_TRANSFERS.load(deps.storage)?;
+    if transfers.is_empty() {
+        return Err(ContractError::NotFound("emergency transfer".to_string()));
+    }
+    let transfer = transfers.remove(0);
+    EMERGENCY_TRANSFERS.save(deps.storage, &transfers)?;
+    if success {
+        return Ok(Response::new());
+    }
+    record_claim(
+        deps.storage,
+        &transfer.receiver,
+        &transfer.asset,
+        transfer.share,
+    )?;
+    Ok(Response::new()
+        .add_attribute_plaintext("emergency_transfer", "failed")
+        .add_event(
+            Event::new("withdraw_asset_skipped")
+                .add_attribute_plaintext("asset", &transfer.asset.address),
+        ))
+}
+
+pub fn try_update_key(
+    deps: DepsMut,
+    info: MessageInfo,
+    viewing_key: String,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.factory.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
+    }
+    // TODO set snip20 viewing key msgs
+    VIEWING_KEY.save(deps.storage, &viewing_key)?;
+    Ok(Response::new().add_attribute_plaintext("action", "update_key"))
+}
+
+pub fn try_receive(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    sender: Addr,
+    from: Addr,
+    amount: Uint256,
+    msg: Option<Binary>,
+) -> Result<Response, ContractError> {
+    if let Some(x) = msg {
+        match from_binary(&x)? {
+            ReceiveMsg::Deposit { depositor } => {
+                try_deposit(deps, env, info, sender, depositor, amount)
+            }
+            ReceiveMsg::StageBasket { depositor } => {
+                try_stage_basket(deps, info, sender, depositor, amount)
+            }
+            ReceiveMsg::LockShares {} => try_lock_shares(deps, env, info, from, amount),
+        }
+    } else {
+        Ok(Response::default())
+    }
+}
+
+pub fn try_deposit(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    sender: Addr,
+    depositor: Addr,
+    amount: Uint256,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if sender != config.factory.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
+    }
+    let deposit_token = DEPOSIT_TOKENS
+        .load(deps.storage)?
+        .into_iter()
+        .find(|x| x.address == info.sender)
+        .ok_or_else(|| ContractError::InvalidToken("must be a valid deposit token".to_string()))?;
+    let amount = Uint128::try_from(amount)?;
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let (mut positions, total_value) =
+        load_positions(deps.storage, &deps.querier, &env, &config, &viewing_key)?;
+    let value = positions
+        .iter()
+        .find(|x| x.position.asset.address == deposit_token.address)
+        .map(|x| token_value(amount, x.price, x.decimals))
+        .ok_or_else(|| ContractError::StalePrice("deposit token".to_string()))?;
+    // The deposit has already arrived, value the portfolio without it
+    let value_before = total_value.saturating_sub(value);
+    check_deposit_limits(
+        deps.storage,
+        &config,
+        &positions,
+        &depositor,
+        value,
+        value_before,
+    )?;
+    record_user_deposit(deps.storage, &depositor, value)?;
+
+    let event = add_event_address(
+        Event::new("deposit")
+            .add_attribute_plaintext("asset", &info.sender)
+            .add_attribute_plaintext("amount", amount.to_string()),
+        "depositor",
+        &depositor,
+        &config.address_privacy,
+    );
+    let response = add_address_attribute(
+        Response::new().add_attribute_plaintext("action", "deposit"),
+        "portfolio",
+        &config.portfolio.snip20.address,
+        &config.address_privacy,
+    )
+    .add_event(event);
+
+    match config.deposit_mode {
+        DepositMode::Queued => {
+            let mut queued = QUEUED_DEPOSITS.load(deps.storage)?;
+            queued.push(QueuedDeposit {
+                depositor,
+                asset: deposit_token.clone(),
+                amount,
+            });
+            QUEUED_DEPOSITS.save(deps.storage, &queued)?;
+            let queued_total = QUEUED_TOTALS
+                .get(deps.storage, &deposit_token.address)
+                .unwrap_or(Uint128::zero());
+            QUEUED_TOTALS.insert(
+                deps.storage,
+                &deposit_token.address,
+                &queued_total.saturating_add(amount),
+            )?;
+            record_snapshot(deps.storage, &deps.querier, &env, &config, &viewing_key)?;
+            Ok(response.add_attribute_plaintext("deposit_mode", "queued"))
+        }
+        DepositMode::Immediate => {
+            apply_strategy(deps.storage, &deps.querier, &config, &mut positions)?;
+            apply_risk_off(deps.storage, &config, &mut positions)?;
+            let deposit_position = positions
+                .iter_mut()
+                .find(|x| x.position.asset.address == deposit_token.address)
+                .ok_or_else(|| ContractError::StalePrice("deposit token".to_string()))?;
+            deposit_position.balance = deposit_position.balance.saturating_sub(amount);
+            deposit_position.value = deposit_position.value.saturating_sub(value);
+            save_snapshot(
+                deps.storage,
+                &deps.querier,
+                &env,
+                &config,
+                &positions,
+                value_before,
+            )?;
+
+            let depositors = vec![DepositShare { depositor, value }];
+            let mut swaps = swap_in_msgs(
+                &deps.querier,
+                &config,
+                &viewing_key,
+                &positions,
+                &deposit_token,
+                amount,
+            )?;
+            let response = response.add_attribute_plaintext("deposit_mode", "immediate");
+            match swaps.pop() {
+                Some(last_swap) => {
+                    PENDING_DEPOSIT.save(
+                        deps.storage,
+                        &PendingDeposit {
+                            depositors,
+                            value_before,
+                        },
+                    )?;
+                    let mut submessages: Vec<SubMsg> = swaps.into_iter().map(SubMsg::new).collect();
+                    submessages.push(SubMsg::reply_on_success(last_swap, DEPOSIT_REPLY_ID));
+                    Ok(response.add_submessages(submessages))
+                }
+                None => Ok(response.add_messages(deposit_mint_msgs(
+                    &deps.querier,
+                    &config,
+                    &depositors,
+                    value,
+                    value_before,
+                )?)),
+            }
+        }
+    }
+}
+
+// Splits a deposit across the target allocation, slices already in the deposit token stay put
+pub fn swap_in_msgs(
+    querier: &QuerierWrapper,
+    config: &Config,
+    viewing_key: &str,
+    positions: &[PositionDetails],
+    asset: &ContractInfo,
+    amount: Uint128,
+) -> Result<Vec<CosmosMsg>, ContractError> {
+    let deposit_position = positions
+        .iter()
+        .find(|x| x.position.asset.address == asset.address)
+        .ok_or_else(|| ContractError::StalePrice("deposit token".to_string()))?;
+    let mut messages = vec![];
+    for target in positions {
+        if target.position.percent == 0 || target.position.asset.address == asset.address {
+            continue;
+        }
+        //TODO remove constants
+        let sell_amount = amount.multiply_ratio(target.position.percent, 100u128);
+        if sell_amount.is_zero() {
+            continue;
+        }
+        let expected_return = token_amount(
+            token_value(
+                sell_amount,
+                deposit_position.price,
+                deposit_position.decimals,
+            ),
+            target.price,
+            target.decimals,
+        )?;
+        let min_expected_return = expected_return.multiply_ratio(7u128, 10u128); // TODO
+        let route = query_route(
+            querier,
+            config.factory.clone(),
+            RouteKey(asset.address.clone(), target.position.asset.address.clone()),
+            viewing_key.to_string(),
+        )?;
+        messages.push(route_msg(
+            route,
+            asset,
+            &target.position.asset,
+            sell_amount,
+            Uint128::try_from(min_expected_return)?,
+        )?);
+    }
+    Ok(messages)
+}
+
+// Sends amount of from along a factory route towards to
+// Portfolio shares are deposited or withdrawn through the factory, min_return is ignored on deposit
+pub fn route_msg(
+    route: Route,
+    from: &ContractInfo,
+    to: &ContractInfo,
+    amount: Uint128,
+    min_return: Uint128,
+) -> StdResult<CosmosMsg> {
+    let msg = match route.kind {
+        RouteKind::Swap => to_binary(&RouterMsg {
+            swap_tokens_for_exact: SwapTokensForExact {
+                expected_return: min_return,
+                path: route.route,
+            },
+        })?,
+        RouteKind::Deposit => to_binary(&FactoryReceiveMsg::Deposit {
+            portfolio_snip20: to.address.clone(),
+            gate_key: None,
+        })?,
+        RouteKind::Withdraw => to_binary(&FactoryReceiveMsg::Withdraw {
+            zap_out: Some(ZapOut {
+                asset: to.clone(),
+                min_output: min_return,
+            }),
+        })?,
+    };
+    snip20::send_msg_with_code_hash(
+        route.router_contract.address.into_string(),
+        Some(route.router_contract.code_hash),
+        amount,
+        Some(msg),
+        None,
+        None,
+        BLOCK_SIZE,
+        from.code_hash.clone(),
+        from.address.clone().into_string(),
+    )
+}
+
+// Shares for the value added by a set of deposits, split by what each depositor put in
+pub fn deposit_mint_msgs(
+    querier: &QuerierWrapper,
+    config: &Config,
+    depositors: &[DepositShare],
+    added_value: Uint256,
+    value_before: Uint256,
+) -> Result<Vec<CosmosMsg>, ContractError> {
+    let total_shares = shares_for_value(querier, config, added_value, value_before)?;
+    let deposited_value = depositors
+        .iter()
+        .fold(Uint256::zero(), |acc, x| acc.saturating_add(x.value));
+    let mut messages = vec![];
+    if deposited_value.is_zero() {
+        return Ok(messages);
+    }
+    for deposit in depositors {
+        let shares = Uint128::try_from(
+            Uint256::from_uint128(total_shares).multiply_ratio(deposit.value, deposited_value),
+        )?;
+        if shares.is_zero() {
+            continue;
+        }
+        messages.push(snip20::mint_msg(
+            deposit.depositor.clone().into_string(),
+            shares,
+            None,
+            None,
+            BLOCK_SIZE,
+            config.portfolio.snip20.code_hash.clone(),
+            config.portfolio.snip20.address.clone().into_string(),
+        )?);
+    }
+    Ok(messages)
+}
+
+pub fn try_finish_deposit(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let pending = PENDING_DEPOSIT.load(deps.storage)?;
+    PENDING_DEPOSIT.remove(deps.storage);
+
+    let (_, total_value) =
+        load_positions(deps.storage, &deps.querier, &env, &config, &viewing_key)?;
+    let added_value = total_value.saturating_sub(pending.value_before);
+    let messages = deposit_mint_msgs(
+        &deps.querier,
+        &config,
+        &pending.depositors,
+        added_value,
+        pending.value_before,
+    )?;
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "mint_deposit_shares")
+        .add_attribute_plaintext("amount", added_value.to_string())
+        .add_messages(messages))
+}
+
+pub fn try_stage_basket(
+    deps: DepsMut,
+    info: MessageInfo,
+    sender: Addr,
+    depositor: Addr,
+    amount: Uint256,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if sender != config.factory.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
+    }
+    if !config
+        .portfolio
+        .config
+        .iter()
+        .any(|x| x.asset.address == info.sender)
+    {
+        return Err(ContractError::InvalidToken(
+            "must be a portfolio asset".to_string(),
+        ));
+    }
+    let amount = Uint128::try_from(amount)?;
+    let staged = STAGED_BASKETS.add_suffix(depositor.as_bytes());
+    let staged_amount = staged
+        .get(deps.storage, &info.sender)
+        .unwrap_or(Uint128::zero());
+    staged.insert(
+        deps.storage,
+        &info.sender,
+        &staged_amount.saturating_add(amount),
+    )?;
+    let staged_total = STAGED_TOTALS
+        .get(deps.storage, &info.sender)
+        .unwrap_or(Uint128::zero());
+    STAGED_TOTALS.insert(
+        deps.storage,
+        &info.sender,
+        &staged_total.saturating_add(amount),
+    )?;
+
+    let event = add_event_address(
+        Event::new("stage_basket")
+            .add_attribute_plaintext("asset", &info.sender)
+            .add_attribute_plaintext("amount", amount.to_string()),
+        "depositor",
+        &depositor,
+        &config.address_privacy,
+    );
+    let response = add_address_attribute(
+        Response::new().add_attribute_plaintext("action", "stage_basket"),
+        "portfolio",
+        &config.portfolio.snip20.address,
+        &config.address_privacy,
+    );
+    Ok(response.add_event(event))
+}
+
+// Mints shares for a staged basket that matches current weights, no swaps are made
+pub fn try_deposit_basket(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    depositor: Addr,
+    min_shares: Option<Uint128>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.factory.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
+    }
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let staged = STAGED_BASKETS.add_suffix(depositor.as_bytes());
+    let basket = staged
+        .iter(deps.storage)?
+        .collect::<StdResult<Vec<(Addr, Uint128)>>>()?;
+    if basket.is_empty() {
+        return Err(ContractError::NotFound("staged basket".to_string()));
+    }
+
+    let (positions, total_value) =
+        load_positions(deps.storage, &deps.querier, &env, &config, &viewing_key)?;
+
+    let mut basket_values = vec![];
+    let mut basket_value = Uint256::zero();
+    for (asset, amount) in &basket {
+        let position = positions
+            .iter()
+            .find(|x| &x.position.asset.address == asset)
+            .ok_or_else(|| ContractError::StalePrice("basket asset".to_string()))?;
+        let value = token_value(*amount, position.price, position.decimals);
+        basket_values.push((asset.clone(), value));
+        basket_value = basket_value.saturating_add(value);
+    }
+    if basket_value.is_zero() {
+        return Err(ContractError::InvalidAmount(
+            "basket has no value".to_string(),
+        ));
+    }
+    check_deposit_limits(
+        deps.storage,
+        &config,
+        &positions,
+        &depositor,
+        basket_value,
+        total_value,
+    )?;
+
+    // An empty portfolio is compared against its targets instead of its current weights
+    let tolerance = Decimal256::from_ratio(config.basket_tolerance_percent, 100u128);
+    for position in &positions {
+        let current_weight = if total_value.is_zero() {
+            Decimal256::from_ratio(position.position.percent, 100u128)
+        } else {
+            Decimal256::from_ratio(position.value, total_value)
+        };
+        let basket_weight = basket_values
+            .iter()
+            .find(|(asset, _)| asset == &position.position.asset.address)
+            .map(|(_, value)| Decimal256::from_ratio(*value, basket_value))
+            .unwrap_or(Decimal256::zero());
+        let drift = if basket_weight > current_weight {
+            basket_weight - current_weight
+        } else {
+            current_weight - basket_weight
+        };
+        if drift > tolerance {
+            return Err(ContractError::InvalidWeights(
+                "basket is not proportional to portfolio weights".to_string(),
+            ));
+        }
+    }
+
+    let shares = shares_for_value(&deps.querier, &config, basket_value, total_value)?;
+    if shares.is_zero() || shares < min_shares.unwrap_or(Uint128::zero()) {
+        return Err(ContractError::SlippageExceeded(
+            "minted shares below minimum".to_string(),
+        ));
+    }
+    record_user_deposit(deps.storage, &depositor, basket_value)?;
+
+    save_snapshot(
+        deps.storage,
+        &deps.querier,
+        &env,
+        &config,
+        &positions,
+        total_value,
+    )?;
+
+    let mut events = vec![];
+    for (asset, amount) in basket {
+        staged.remove(deps.storage, &asset)?;
+        let staged_total = STAGED_TOTALS
+            .get(deps.storage, &asset)
+            .unwrap_or(Uint128::zero());
+        STAGED_TOTALS.insert(deps.storage, &asset, &staged_total.saturating_sub(amount))?;
+        events.push(
+            Event::new("deposit_basket_asset")
+                .add_attribute_plaintext("asset", &asset)
+                .add_attribute_plaintext("amount", amount.to_string()),
+        );
+    }
+
+    let mint = snip20::mint_msg(
+        depositor.clone().into_string(),
+        shares,
+        None,
+        None,
+        BLOCK_SIZE,
+        config.portfolio.snip20.code_hash.clone(),
+        config.portfolio.snip20.address.clone().into_string(),
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "deposit_basket")
+        .add_attribute_plaintext("amount", shares.to_string());
+    let response = add_address_attribute(
+        response,
+        "portfolio",
+        &config.portfolio.snip20.address,
+        &config.address_privacy,
+    );
+    let response =
+        add_address_attribute(response, "depositor", &depositor, &config.address_privacy);
+    Ok(response.add_events(events).add_message(mint))
+}
+
+pub fn try_cancel_basket(
+    deps: DepsMut,
+    info: MessageInfo,
+    depositor: Addr,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.factory.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
+    }
+    let staged = STAGED_BASKETS.add_suffix(depositor.as_bytes());
+    let basket = staged
+        .iter(deps.storage)?
+        .collect::<StdResult<Vec<(Addr, Uint128)>>>()?;
+
+    let mut messages = vec![];
+    for (asset, amount) in basket {
+        staged.remove(deps.storage, &asset)?;
+        let staged_total = STAGED_TOTALS
+            .get(deps.storage, &asset)
+            .unwrap_or(Uint128::zero());
+        STAGED_TOTALS.insert(deps.storage, &asset, &staged_total.saturating_sub(amount))?;
+        let position = config
+            .portfolio
+            .config
+            .iter()
+            .find(|x| x.asset.address == asset)
+            .ok_or_else(|| ContractError::InvalidToken("must be a portfolio asset".to_string()))?;
+        messages.push(snip20::transfer_msg(
+            depositor.clone().into_string(),
+            amount,
+            None,
+            None,
+            BLOCK_SIZE,
+            position.asset.code_hash.clone(),
+            asset.into_string(),
+        )?);
+    }
+
+    let response = add_address_attribute(
+        Response::new().add_attribute_plaintext("action", "cancel_basket"),
+        "depositor",
+        &depositor,
+        &config.address_privacy,
+    );
+    Ok(response.add_messages(messages))
+}
+
+pub fn try_propose_weights(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    weights: Vec<PortfolioConfig>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.portfolio.creator {
+        return Err(ContractError::Unauthorized("portfolio creator".to_string()));
+    }
+    let (pending, messages) = queue_weights(deps.storage, &deps.querier, &env, &config, weights)?;
+
+    let event = pending.config.iter().fold(
+        Event::new("propose_weights")
+            .add_attribute_plaintext("effective_at", pending.effective_at.to_string()),
+        |event, position| {
+            event
+                .add_attribute_plaintext("asset", &position.asset.address)
+                .add_attribute_plaintext("percent", position.percent.to_string())
+        },
+    );
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "propose_weights")
+        .add_event(event)
+        .add_messages(messages))
+}
+
+pub fn try_set_permit_queries(
+    deps: DepsMut,
+    info: MessageInfo,
+    enabled: bool,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if info.sender != config.factory.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
+    }
+    config.permit_queries = enabled;
+    CONFIG.save(deps.storage, &config)?;
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "set_permit_queries")
+        .add_attribute_plaintext("enabled", enabled.to_string()))
+}
+
+pub fn try_set_limits(
+    deps: DepsMut,
+    info: MessageInfo,
+    limits: PortfolioLimits,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    if info.sender != config.factory.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
+    }
+    config.limits = limits;
+    CONFIG.save(deps.storage, &config)?;
+    Ok(Response::new().add_attribute_plaintext("action", "set_limits"))
+}
+
+pub fn try_revoke_permit(
+    deps: DepsMut,
+    info: MessageInfo,
+    permit_name: String,
+) -> Result<Response, ContractError> {
+    RevokedPermits::revoke_permit(
+        deps.storage,
+        PREFIX_REVOKED_PERMITS,
+        info.sender.as_str(),
+        &permit_name,
+    );
+    Ok(Response::new().add_attribute_plaintext("action", "revoke_permit"))
+}
+
+pub fn try_set_risk_off(
+    deps: DepsMut,
+    info: MessageInfo,
+    active: bool,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.portfolio.creator {
+        return Err(ContractError::Unauthorized("portfolio creator".to_string()));
+    }
+    if config.risk_off.is_none() {
+        return Err(ContractError::InvalidConfig(
+            "risk-off is not configured".to_string(),
+        ));
+    }
+    RISK_OFF.save(deps.storage, &active)?;
+    if !active {
+        // The peak restarts from the next snapshot so the drawdown does not trigger again at once
+        PEAK_NAV.remove(deps.storage);
+    }
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "set_risk_off")
+        .add_event(
+            Event::new("risk_off")
+                .add_attribute_plaintext("active", active.to_string())
+                .add_attribute_plaintext("trigger", "manual"),
+        ))
+}
+
+// Turns risk-off on once the share price is drawdown_percent below its peak
+pub fn check_drawdown(
+    storage: &mut dyn Storage,
+    config: &Config,
+    nav: &Nav,
+) -> Result<Option<Event>, ContractError> {
+    let drawdown_percent = match config.risk_off.as_ref().and_then(|x| x.drawdown_percent) {
+        Some(drawdown_percent) => drawdown_percent,
+        None => return Ok(None),
+    };
+    if RISK_OFF.may_load(storage)?.unwrap_or(false) {
+        return Ok(None);
+    }
+    let peak = PEAK_NAV.may_load(storage)?.unwrap_or(Uint256::zero());
+    //TODO remove constants
+    let drawdown = peak
+        .saturating_sub(nav.nav_per_share)
+        .saturating_mul(Uint256::from(100u128));
+    if peak.is_zero() || drawdown < peak.saturating_mul(Uint256::from(drawdown_percent)) {
+        return Ok(None);
+    }
+    RISK_OFF.save(storage, &true)?;
+    Ok(Some(
+        Event::new("risk_off")
+            .add_attribute_plaintext("active", "true")
+            .add_attribute_plaintext("trigger", "drawdown")
+            .add_attribute_plaintext("peak_nav_per_share", peak.to_string())
+            .add_attribute_plaintext("nav_per_share", nav.nav_per_share.to_string()),
+    ))
+}
+
+pub fn try_register_stray_token(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: ContractInfo,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.portfolio.creator && info.sender != config.admin {
+        return Err(ContractError::Unauthorized(
+            "portfolio creator or admin".to_string(),
+        ));
+    }
+    check_stray_token(deps.storage, &config, &token.address)?;
+    STRAY_TOKENS.insert(deps.storage, &token.address, &token)?;
+    let msg = snip20::set_viewing_key_msg(
+        VIEWING_KEY.load(deps.storage)?,
+        None,
+        BLOCK_SIZE,
+        token.code_hash,
+        token.address.clone().into_string(),
+    )?;
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "register_stray_token")
+        .add_attribute_plaintext("token", &token.address)
+        .add_message(msg))
+}
+
+pub fn try_rescue_tokens(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    token: Addr,
+    amount: Option<Uint128>,
+    sweep: bool,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.portfolio.creator && info.sender != config.admin {
+        return Err(ContractError::Unauthorized(
+            "portfolio creator or admin".to_string(),
+        ));
+    }
+    // Checked again, the token may have joined the allocation since it was registered
+    check_stray_token(deps.storage, &config, &token)?;
+    let token = STRAY_TOKENS
+        .get(deps.storage, &token)
+        .ok_or_else(|| ContractError::NotFound("stray token".to_string()))?;
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let amount = match amount {
+        Some(amount) => amount,
+        None => {
+            snip20::balance_query(
+                deps.querier,
+                env.contract.address.clone().into_string(),
+                viewing_key.clone(),
+                BLOCK_SIZE,
+                token.code_hash.clone(),
+                token.address.clone().into_string(),
+            )?
+            .amount
+        }
+    };
+    if amount.is_zero() {
+        return Err(ContractError::InvalidAmount(
+            "nothing to rescue".to_string(),
+        ));
+    }
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "rescue_tokens")
+        .add_attribute_plaintext("token", &token.address)
+        .add_attribute_plaintext("amount", amount.to_string());
+    if sweep {
+        let msg = snip20::transfer_msg(
+            config.admin.clone().into_string(),
+            amount,
+            None,
+            None,
+            BLOCK_SIZE,
+            token.code_hash,
+            token.address.into_string(),
+        )?;
+        return Ok(response
+            .add_attribute_plaintext("rescue", "sweep")
+            .add_message(msg));
+    }
+
+    // Swapped like a deposit that mints no shares, so the value goes to every holder
+    let (mut positions, _) =
+        load_positions(deps.storage, &deps.querier, &env, &config, &viewing_key)?;
+    apply_strategy(deps.storage, &deps.querier, &config, &mut positions)?;
+    apply_risk_off(deps.storage, &config, &mut positions)?;
+    let price = query_prices(
+        &deps.querier,
+        config.factory.clone(),
+        vec![token.address.clone()],
+        viewing_key.clone(),
+    )?
+    .into_iter()
+    .find(|x| x.asset == token.address)
+    .ok_or_else(|| ContractError::StalePrice("stray token".to_string()))?;
+    positions.push(PositionDetails {
+        position: PortfolioConfig {
+            percent: 0,
+            asset: token.clone(),
+            tolerance: None,
+        },
+        balance: amount,
+        value: Uint256::zero(),
+        price: price.price,
+        decimals: price.decimals,
+    });
+    let messages = swap_in_msgs(
+        &deps.querier,
+        &config,
+        &viewing_key,
+        &positions,
+        &token,
+        amount,
+    )?;
+    Ok(response
+        .add_attribute_plaintext("rescue", "swap")
+        .add_messages(messages))
+}
+
+// Allocation assets, deposit tokens, incoming weights and locked shares can never be rescued
+fn check_stray_token(
+    storage: &dyn Storage,
+    config: &Config,
+    token: &Addr,
+) -> Result<(), ContractError> {
+    let pending = PENDING_WEIGHTS
+        .may_load(storage)?
+        .map(|x| x.config)
+        .unwrap_or_default();
+    let held = held_assets(storage, config)?
+        .iter()
+        .chain(pending.iter())
+        .any(|x| &x.asset.address == token)
+        || config.portfolio.snip20.address == *token
+        || CLAIM_POOLS.contains(storage, token)
+        || matches!(&config.risk_off, Some(x) if x.safe_asset.address == *token);
+    if held {
+        return Err(ContractError::RescueForbidden);
+    }
+    Ok(())
+}
+
+pub fn try_cancel_weights(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.portfolio.creator {
+        return Err(ContractError::Unauthorized("portfolio creator".to_string()));
+    }
+    if PENDING_WEIGHTS.may_load(deps.storage)?.is_none() {
+        return Err(ContractError::NotFound("pending weight change".to_string()));
+    }
+    PENDING_WEIGHTS.remove(deps.storage);
+    Ok(Response::new().add_attribute_plaintext("action", "cancel_weights"))
+}
+
+// Targets must cover the whole portfolio once, each asset at most once
+pub fn validate_weights(weights: &[PortfolioConfig]) -> Result<(), ContractError> {
+    if weights.is_empty() {
+        return Err(ContractError::InvalidWeights(
+            "portfolio must have at least one asset".to_string(),
+        ));
+    }
+    for (i, position) in weights.iter().enumerate() {
+        if weights[..i]
+            .iter()
+            .any(|x| x.asset.address == position.asset.address)
+        {
+            return Err(ContractError::InvalidWeights(
+                "duplicate portfolio asset".to_string(),
+            ));
+        }
+    }
+    //TODO remove constants
+    if weights.iter().map(|x| x.percent).sum::<u128>() != 100 {
+        return Err(ContractError::InvalidWeights(
+            "portfolio weights must add up to 100".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Nested portfolios are only known to the factory, which rejects cycles and deep nesting
+pub fn check_weights_nesting(
+    querier: &QuerierWrapper,
+    config: &Config,
+    weights: &[PortfolioConfig],
+) -> Result<(), ContractError> {
+    match query_check_nesting(
+        querier,
+        config.factory.clone(),
+        config.portfolio.snip20.address.clone(),
+        weights.to_vec(),
+    )? {
+        Some(reason) => Err(ContractError::InvalidWeights(reason)),
+        None => Ok(()),
+    }
+}
+
+// Stores validated weights to take effect after the delay, replacing any pending change
+// Returns viewing key messages for assets the portfolio does not hold yet
+pub fn queue_weights(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &Config,
+    weights: Vec<PortfolioConfig>,
+) -> Result<(PendingWeights, Vec<CosmosMsg>), ContractError> {
+    validate_weights(&weights)?;
+    check_weights_nesting(querier, config, &weights)?;
+    let viewing_key = VIEWING_KEY.load(storage)?;
+    let held_assets = held_assets(storage, config)?;
+    let mut messages = vec![];
+    for position in &weights {
+        if !held_assets
+            .iter()
+            .any(|x| x.asset.address == position.asset.address)
+        {
+            messages.push(snip20::set_viewing_key_msg(
+                viewing_key.clone(),
+                None,
+                BLOCK_SIZE,
+                position.asset.code_hash.clone(),
+                position.asset.address.clone().into_string(),
+            )?);
+        }
+    }
+    let pending = PendingWeights {
+        config: weights,
+        effective_at: env
+            .block
+            .time
+            .seconds()
+            .saturating_add(config.weight_change_delay),
+    };
+    PENDING_WEIGHTS.save(storage, &pending)?;
+    Ok((pending, messages))
+}
+
+// Swaps in pending weights once their delay has passed and tells the factory
+// Current assets missing from the new weights stay at 0% until they are sold
+// Weights whose nesting became invalid while pending are dropped
+pub fn apply_pending_weights(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &mut Config,
+) -> Result<(Option<Event>, Vec<CosmosMsg>), ContractError> {
+    let pending = match PENDING_WEIGHTS.may_load(storage)? {
+        Some(pending) if pending.effective_at <= env.block.time.seconds() => pending,
+        _ => return Ok((None, vec![])),
+    };
+    PENDING_WEIGHTS.remove(storage);
+    if let Err(ContractError::InvalidWeights(reason)) =
+        check_weights_nesting(querier, config, &pending.config)
+    {
+        return Ok((
+            Some(Event::new("reject_weights").add_attribute_plaintext("reason", reason)),
+            vec![],
+        ));
+    }
+
+    let mut weights = pending.config;
+    for position in &config.portfolio.config {
+        if !weights
+            .iter()
+            .any(|x| x.asset.address == position.asset.address)
+        {
+            weights.push(PortfolioConfig {
+                percent: 0,
+                asset: position.asset.clone(),
+                tolerance: None,
+            });
+        }
+    }
+    config.portfolio.config = weights;
+    CONFIG.save(storage, config)?;
+    let msg = factory_execute_msg(
+        config.factory.clone(),
+        &FactoryExecuteMsg::SetPortfolioWeights {
+            portfolio_snip20: config.portfolio.snip20.address.clone(),
+            config: config.portfolio.config.clone(),
+        },
+    )?;
+
+    Ok((
+        Some(config.portfolio.config.iter().fold(
+            Event::new("apply_weights"),
+            |event, position| {
+                event
+                    .add_attribute_plaintext("asset", &position.asset.address)
+                    .add_attribute_plaintext("percent", position.percent.to_string())
+            },
+        )),
+        vec![msg],
+    ))
+}
+
+pub fn try_lock_shares(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    from: Addr,
+    amount: Uint256,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.governance.is_none() {
+        return Err(ContractError::Governance("not enabled".to_string()));
+    }
+    if info.sender != config.portfolio.snip20.address {
+        return Err(ContractError::InvalidToken(
+            "must be the portfolio share token".to_string(),
+        ));
+    }
+    let amount = Uint128::try_from(amount)?;
+    let locked = LOCKED_SHARES
+        .get(deps.storage, &from)
+        .unwrap_or(Uint128::zero())
+        .saturating_add(amount);
+    LOCKED_SHARES.insert(deps.storage, &from, &locked)?;
+    write_checkpoint(
+        deps.storage,
+        &SHARE_CHECKPOINTS.add_suffix(from.as_bytes()),
+        env.block.height,
+        locked,
+    )?;
+    let total = power_at(deps.storage, &TOTAL_CHECKPOINTS, u64::MAX)?.saturating_add(amount);
+    write_checkpoint(deps.storage, &TOTAL_CHECKPOINTS, env.block.height, total)?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "lock_shares")
+        .add_attribute_plaintext("amount", amount.to_string());
+    Ok(add_address_attribute(
+        response,
+        "holder",
+        &from,
+        &config.address_privacy,
+    ))
+}
+
+pub fn try_unlock_shares(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let locked = LOCKED_SHARES
+        .get(deps.storage, &info.sender)
+        .unwrap_or(Uint128::zero());
+    if amount > locked {
+        return Err(ContractError::Governance(
+            "not enough locked shares".to_string(),
+        ));
+    }
+    let locked = locked.saturating_sub(amount);
+    LOCKED_SHARES.insert(deps.storage, &info.sender, &locked)?;
+    write_checkpoint(
+        deps.storage,
+        &SHARE_CHECKPOINTS.add_suffix(info.sender.as_bytes()),
+        env.block.height,
+        locked,
+    )?;
+    let total = power_at(deps.storage, &TOTAL_CHECKPOINTS, u64::MAX)?.saturating_sub(amount);
+    write_checkpoint(deps.storage, &TOTAL_CHECKPOINTS, env.block.height, total)?;
+
+    let msg = snip20::transfer_msg(
+        info.sender.clone().into_string(),
+        amount,
+        None,
+        None,
+        BLOCK_SIZE,
+        config.portfolio.snip20.code_hash.clone(),
+        config.portfolio.snip20.address.clone().into_string(),
+    )?;
+    let response = Response::new()
+        .add_attribute_plaintext("action", "unlock_shares")
+        .add_attribute_plaintext("amount", amount.to_string());
+    Ok(
+        add_address_attribute(response, "holder", &info.sender, &config.address_privacy)
+            .add_message(msg),
+    )
+}
+
+pub fn try_create_proposal(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    action: ProposalAction,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let governance = config
+        .governance
+        .clone()
+        .ok_or_else(|| ContractError::Governance("not enabled".to_string()))?;
+    let locked = LOCKED_SHARES
+        .get(deps.storage, &info.sender)
+        .unwrap_or(Uint128::zero());
+    if locked.is_zero() || locked < governance.proposal_min_shares {
+        return Err(ContractError::Governance(
+            "not enough locked shares to propose".to_string(),
+        ));
+    }
+    match &action {
+        ProposalAction::SetWeights { config: weights } => {
+            validate_weights(weights)?;
+            check_weights_nesting(&deps.querier, &config, weights)?;
+        }
+        //TODO remove constants
+        ProposalAction::SetWithdrawFee { fee } if *fee > 100 => {
+            return Err(ContractError::InvalidConfig(
+                "fee must not exceed 100".to_string(),
+            ));
+        }
+        ProposalAction::SetTolerance { tolerance_percent } if *tolerance_percent > 100 => {
+            return Err(ContractError::InvalidConfig(
+                "tolerance must not exceed 100".to_string(),
+            ));
+        }
+        _ => {}
+    }
+    let total_power = power_at(deps.storage, &TOTAL_CHECKPOINTS, env.block.height)?;
+    if total_power.is_zero() {
+        return Err(ContractError::Governance(
+            "no shares were locked before this block".to_string(),
+        ));
+    }
+
+    let id = PROPOSALS.get_len(deps.storage)?;
+    let proposal = Proposal {
+        id,
+        action,
+        snapshot_height: env.block.height,
+        end_time: env
+            .block
+            .time
+            .seconds()
+            .saturating_add(governance.voting_period),
+        yes: Uint128::zero(),
+        no: Uint128::zero(),
+        abstain: Uint128::zero(),
+        total_power,
+        status: ProposalStatus::Open,
+    };
+    PROPOSALS.push(deps.storage, &proposal)?;
+
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "create_proposal")
+        .add_attribute_plaintext("proposal_id", id.to_string())
+        .add_attribute_plaintext("end_time", proposal.end_time.to_string()))
+}
+
+pub fn try_cast_vote(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    proposal_id: u32,
+    vote: VoteOption,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let mut proposal = load_proposal(deps.storage, proposal_id)?;
+    if proposal.status != ProposalStatus::Open || env.block.time.seconds() >= proposal.end_time {
+        return Err(ContractError::Governance("voting has ended".to_string()));
+    }
+    let votes = VOTES.add_suffix(&proposal_id.to_be_bytes());
+    if votes.contains(deps.storage, &info.sender) {
+        return Err(ContractError::Governance("already voted".to_string()));
+    }
+    let power = power_at(
+        deps.storage,
+        &SHARE_CHECKPOINTS.add_suffix(info.sender.as_bytes()),
+        proposal.snapshot_height,
+    )?;
+    if power.is_zero() {
+        return Err(ContractError::Governance(
+            "no voting power at snapshot".to_string(),
+        ));
+    }
+    match vote {
+        VoteOption::Yes => proposal.yes = proposal.yes.saturating_add(power),
+        VoteOption::No => proposal.no = proposal.no.saturating_add(power),
+        VoteOption::Abstain => proposal.abstain = proposal.abstain.saturating_add(power),
+    }
+    votes.insert(deps.storage, &info.sender, &vote)?;
+    PROPOSALS.set_at(deps.storage, proposal_id, &proposal)?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "cast_vote")
+        .add_attribute_plaintext("proposal_id", proposal_id.to_string());
+    Ok(add_address_attribute(
+        response,
+        "voter",
+        &info.sender,
+        &config.address_privacy,
+    ))
+}
+
+pub fn try_execute_proposal(
+    deps: DepsMut,
+    env: Env,
+    proposal_id: u32,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
+    let governance = config
+        .governance
+        .clone()
+        .ok_or_else(|| ContractError::Governance("not enabled".to_string()))?;
+    let mut proposal = load_proposal(deps.storage, proposal_id)?;
+    if proposal.status != ProposalStatus::Open {
+        return Err(ContractError::Governance(
+            "proposal is already closed".to_string(),
+        ));
+    }
+    if env.block.time.seconds() < proposal.end_time {
+        return Err(ContractError::Governance(
+            "voting has not ended".to_string(),
+        ));
+    }
+
+    let turnout = proposal
+        .yes
+        .saturating_add(proposal.no)
+        .saturating_add(proposal.abstain);
+    //TODO remove constants
+    let quorum_met = Uint256::from_uint128(turnout).saturating_mul(Uint256::from(100u128))
+        >= Uint256::from_uint128(proposal.total_power)
+            .saturating_mul(Uint256::from(governance.quorum_percent));
+    let threshold_met = !proposal.yes.is_zero()
+        && Uint256::from_uint128(proposal.yes).saturating_mul(Uint256::from(100u128))
+            > Uint256::from_uint128(proposal.yes.saturating_add(proposal.no))
+                .saturating_mul(Uint256::from(governance.threshold_percent));
+
+    let messages = if quorum_met && threshold_met {
+        proposal.status = ProposalStatus::Executed;
+        apply_proposal_action(
+            deps.storage,
+            &deps.querier,
+            &env,
+            &mut config,
+            &proposal.action,
+        )?
+    } else {
+        proposal.status = ProposalStatus::Rejected;
+        vec![]
+    };
+    PROPOSALS.set_at(deps.storage, proposal_id, &proposal)?;
+
+    let status = match proposal.status {
+        ProposalStatus::Executed => "executed",
+        _ => "rejected",
+    };
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "execute_proposal")
+        .add_attribute_plaintext("proposal_id", proposal_id.to_string())
+        .add_attribute_plaintext("status", status)
+        .add_messages(messages))
+}
+
+// Weight changes go through the creator's timelocked path
+pub fn apply_proposal_action(
+    storage: &mut dyn Storage,
+    querier: &QuerierWrapper,
+    env: &Env,
+    config: &mut Config,
+    action: &ProposalAction,
+) -> Result<Vec<CosmosMsg>, ContractError> {
+    match action {
+        ProposalAction::SetWeights { config: weights } => {
+            let (_, messages) = queue_weights(storage, querier, env, config, weights.clone())?;
+            return Ok(messages);
+        }
+        ProposalAction::SetWithdrawFee { fee } => config.withdraw_fee = Some(*fee),
+        ProposalAction::SetTolerance { tolerance_percent } => {
+            config.tolerance_percent = Some(*tolerance_percent)
+        }
+    }
+    CONFIG.save(storage, config)?;
+    Ok(vec![])
+}
+
+pub fn load_proposal(storage: &dyn Storage, proposal_id: u32) -> Result<Proposal, ContractError> {
+    if proposal_id >= PROPOSALS.get_len(storage)? {
+        return Err(ContractError::NotFound("proposal".to_string()));
+    }
+    Ok(PROPOSALS.get_at(storage, proposal_id)?)
+}
+
+// Amount of the last checkpoint written before height
+pub fn power_at(
+    storage: &dyn Storage,
+    checkpoints: &AppendStore<Checkpoint>,
+    height: u64,
+) -> Result<Uint128, ContractError> {
+    let mut low = 0u32;
+    let mut high = checkpoints.get_len(storage)?;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if checkpoints.get_at(storage, mid)?.height < height {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    if low == 0 {
+        return Ok(Uint128::zero());
+    }
+    Ok(checkpoints.get_at(storage, low - 1)?.amount)
+}
+
+// Checkpoints are kept one per block, later changes in the same block overwrite it
+pub fn write_checkpoint(
+    storage: &mut dyn Storage,
+    checkpoints: &AppendStore<Checkpoint>,
+    height: u64,
+    amount: Uint128,
+) -> StdResult<()> {
+    let len = checkpoints.get_len(storage)?;
+    let checkpoint = Checkpoint { height, amount };
+    if len > 0 && checkpoints.get_at(storage, len - 1)?.height == height {
+        return checkpoints.set_at(storage, len - 1, &checkpoint);
+    }
+    checkpoints.push(storage, &checkpoint)
+}
+
+#[entry_point]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    match msg.id {
+        ZAP_OUT_REPLY_ID => try_finish_zap_out(deps, env),
+        DEPOSIT_REPLY_ID => try_finish_deposit(deps, env),
+        EMERGENCY_TRANSFER_REPLY_ID => {
+            try_finish_emergency_transfer(deps, matches!(msg.result, SubMsgResult::Ok(_)))
+        }
+        _ => Err(ContractError::UnknownReply(msg.id)),
+    }
+}
+
+#[entry_point]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
+    let response = match msg {
+        QueryMsg::GetConfig {} => to_binary(&get_config(deps)?),
+        QueryMsg::GetFees {} => to_binary(&get_fees(deps)?),
+        QueryMsg::GetBalances {} => {
+            check_public(deps, None)?;
+            to_binary(&get_balances(deps, env)?)
+        }
+        QueryMsg::GetWeights {
+            tolerance_percent,
+            key,
+        } => {
+            check_public(deps, key)?;
+            to_binary(&get_weights(deps, env, tolerance_percent)?)
+        }
+        QueryMsg::GetNav { key } => {
+            check_public(deps, key)?;
+            to_binary(&get_nav(deps, env)?)
+        }
+        QueryMsg::GetCapacity { address, key } => {
+            check_public(deps, key.clone())?;
+            if address.is_some() && key != Some(VIEWING_KEY.load(deps.storage)?) {
+                return Err(ContractError::InvalidViewingKey);
+            }
+            to_binary(&get_capacity(deps, env, address)?)
+        }
+        QueryMsg::WithPermit { permit, query } => return permit_query(deps, env, permit, query),
+        QueryMsg::GetDepositTokens {} => to_binary(&get_deposit_tokens(deps)?),
+        QueryMsg::GetPendingWeights {} => to_binary(&get_pending_weights(deps)?),
+        QueryMsg::GetStrayTokens {} => {
+            check_public(deps, None)?;
+            to_binary(&get_stray_tokens(deps, env)?)
+        }
+        QueryMsg::GetRiskOff {} => {
+            check_public(deps, None)?;
+            to_binary(&get_risk_off(deps)?)
+        }
+        QueryMsg::GetProposal { proposal_id } => to_binary(&QueryAnswer::GetProposal {
+            proposal: load_proposal(deps.storage, proposal_id)?,
+        }),
+        QueryMsg::GetProposals { page, page_size } => {
+            to_binary(&get_proposals(deps, page, page_size)?)
+        }
+        QueryMsg::GetHistory {
+            start_time,
+            end_time,
+            page,
+            page_size,
+        } => {
+            check_public(deps, None)?;
+            to_binary(&get_history(deps, start_time, end_time, page, page_size)?)
+        }
+    };
+    Ok(response?)
+}
+
+// The user's remaining capacity is only shown with the portfolio's viewing key or a permit
+fn get_capacity(deps: Deps, env: Env, address: Option<Addr>) -> Result<QueryAnswer, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let user_remaining = match address {
+        Some(address) => {
+            let deposited = USER_DEPOSITS
+                .get(deps.storage, &address)
+                .unwrap_or(Uint256::zero());
+            config
+                .limits
+                .user_cap
+                .map(|cap| cap.saturating_sub(deposited))
+        }
+        None => None,
+    };
+    let tvl_remaining = match config.limits.tvl_cap {
+        Some(tvl_cap) => {
+            let (positions, total_value) =
+                load_positions(deps.storage, &deps.querier, &env, &config, &viewing_key)?;
+            let tvl = total_value.saturating_add(queued_value(deps.storage, &positions));
+            Some(tvl_cap.saturating_sub(tvl))
+        }
+        None => None,
+    };
+    Ok(QueryAnswer::GetCapacity {
+        capacity: Capacity {
+            limits: config.limits,
+            tvl_remaining,
+            user_remaining,
+        },
+    })
+}
+
+// Plain queries are closed once permits are required, the factory and nested parents
+// get through with the portfolio's viewing key
+fn check_public(deps: Deps, key: Option<String>) -> Result<(), ContractError> {
+    if !CONFIG.load(deps.storage)?.permit_queries {
+        return Ok(());
+    }
+    match key {
+        Some(key) if key == VIEWING_KEY.load(deps.storage)? => Ok(()),
+        _ => Err(ContractError::PermitRequired),
+    }
+}
+
+// The signer must be the creator or pass the factory's access check
+fn permit_query(
+    deps: Deps,
+    env: Env,
+    permit: Permit,
+    query: QueryWithPermit,
+) -> Result<Binary, ContractError> {
+    let account = Addr::unchecked(permit::validate(
+        deps,
+        PREFIX_REVOKED_PERMITS,
+        &permit,
+        env.contract.address.to_string(),
+        None,
+    )?);
+    if !permit.check_permission(&TokenPermissions::Balance) {
+        return Err(ContractError::AccessDenied(
+            "permit has no balance permission".to_string(),
+        ));
+    }
+    let config = CONFIG.load(deps.storage)?;
+    if account != config.portfolio.creator
+        && !query_is_allowed(
+            &deps.querier,
+            config.factory,
+            config.portfolio.snip20.address,
+            account.clone(),
+            VIEWING_KEY.load(deps.storage)?,
+        )?
+    {
+        return Err(ContractError::AccessDenied(
+            "permit signer is not allowed".to_string(),
+        ));
+    }
+
+    let response = match query {
+        QueryWithPermit::GetBalances {} => to_binary(&get_balances(deps, env)?),
+        QueryWithPermit::GetWeights { tolerance_percent } => {
+            to_binary(&get_weights(deps, env, tolerance_percent)?)
+        }
+        QueryWithPermit::GetNav {} => to_binary(&get_nav(deps, env)?),
+        QueryWithPermit::GetRiskOff {} => to_binary(&get_risk_off(deps)?),
+        QueryWithPermit::GetCapacity {} => to_binary(&get_capacity(deps, env, Some(account))?),
+        QueryWithPermit::GetHistory {
+            start_time,
+            end_time,
+            page,
+            page_size,
+        } => to_binary(&get_history(deps, start_time, end_time, page, page_size)?),
+    };
+    Ok(response?)
+}
+
+fn get_deposit_tokens(deps: Deps) -> Result<QueryAnswer, ContractError> {
+    Ok(QueryAnswer::GetDepositTokens {
+        deposit_tokens: DEPOSIT_TOKENS.load(deps.storage)?,
+    })
+}
+
+fn get_stray_tokens(deps: Deps, env: Env) -> Result<QueryAnswer, ContractError> {
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let mut balances = vec![];
+    for token in STRAY_TOKENS.iter(deps.storage)? {
+        let (_, token) = token?;
+        let balance = snip20::balance_query(
+            deps.querier,
+            env.contract.address.clone().into_string(),
+            viewing_key.clone(),
+            BLOCK_SIZE,
+            token.code_hash,
+            token.address.clone().into_string(),
+        )?;
+        balances.push(BalanceItem {
+            asset: token.address,
+            amount: balance.amount,
+        });
+    }
+    Ok(QueryAnswer::GetStrayTokens { balances })
+}
+
+// The peak share price reveals NAV, so it is closed along with GetNav
+fn get_risk_off(deps: Deps) -> Result<QueryAnswer, ContractError> {
+    Ok(QueryAnswer::GetRiskOff {
+        active: RISK_OFF.may_load(deps.storage)?.unwrap_or(false),
+        peak_nav_per_share: PEAK_NAV.may_load(deps.storage)?,
+    })
+}
+
+fn get_pending_weights(deps: Deps) -> Result<QueryAnswer, ContractError> {
+    Ok(QueryAnswer::GetPendingWeights {
+        pending: PENDING_WEIGHTS.may_load(deps.storage)?,
+    })
+}
+
+fn get_proposals(deps: Deps, page: u32, page_size: u32) -> Result<QueryAnswer, ContractError> {
+    Ok(QueryAnswer::GetProposals {
+        proposals: PROPOSALS.paging(deps.storage, page, page_size)?,
+        total: PROPOSALS.get_len(deps.storage)?,
+    })
+}
+
+fn get_config(deps: Deps) -> Result<QueryAnswer, ContractError> {
+    let state = CONFIG.load(deps.storage)?;
+    Ok(QueryAnswer::GetConfig { config: state })
+}
+
+fn get_balances(deps: Deps, env: Env) -> Result<QueryAnswer, ContractError> {
+    let state = CONFIG.load(deps.storage)?;
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let mut balances = vec![];
+    for position in held_assets(deps.storage, &state)? {
+        let balance = snip20::balance_query(
+            deps.querier,
+            env.contract.address.clone().into_string(),
+            viewing_key.clone(),
+            BLOCK_SIZE,
+            position.asset.code_hash,
+            position.asset.address.clone().into_string(),
+        )?;
+        balances.push(BalanceItem {
+            asset: position.asset.address,
+            amount: balance.amount,
+        })
+    }
+    Ok(QueryAnswer::GetBalances { balances })
+}
+
+fn get_weights(
+    deps: Deps,
+    env: Env,
+    tolerance_percent: Option<u128>,
+) -> Result<QueryAnswer, ContractError> {
+    let state = CONFIG.load(deps.storage)?;
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let (mut positions, total_value) =
+        load_positions(deps.storage, &deps.querier, &env, &state, &viewing_key)?;
+    apply_strategy(deps.storage, &deps.querier, &state, &mut positions)?;
+    apply_risk_off(deps.storage, &state, &mut positions)?;
+
+    // Same targets swap_in_msgs sells each queued deposit into
+    let deposit_swaps = QUEUED_DEPOSITS
+        .load(deps.storage)?
+        .iter()
+        .take(MAX_QUEUED_DEPOSITS_PER_UPDATE)
+        .map(|deposit| {
+            positions
+                .iter()
+                .filter(|target| {
+                    target.position.percent != 0
+                        && target.position.asset.address != deposit.asset.address
+                        && !deposit
+                            .amount
+                            .multiply_ratio(target.position.percent, 100u128)
+                            .is_zero()
+                })
+                .count() as u32
+        })
+        .sum();
+
+    // Same tolerance Update would use, the portfolio's own setting wins
+    let tolerance_percent = state.tolerance_percent.or(tolerance_percent).unwrap_or(0);
+    let mut weights = vec![];
+    let mut rebalance_due = false;
+    for position in positions {
+        //TODO remove constants
+        let target_value = total_value.multiply_ratio(position.position.percent, 100u128);
+        let tolerance = tolerance_amount(
+            &position.position,
+            target_value,
+            total_value,
+            tolerance_percent,
+        );
+        let current_weight = if total_value.is_zero() {
+            Decimal256::zero()
+        } else {
+            Decimal256::from_ratio(position.value, total_value)
+        };
+        let target_weight = Decimal256::from_ratio(position.position.percent, 100u128);
+        let over_target = current_weight > target_weight;
+        let drift = if over_target {
+            current_weight - target_weight
+        } else {
+            target_weight - current_weight
+        };
+        let out_of_band = position.value > target_value.saturating_add(tolerance)
+            || position.value < target_value.saturating_sub(tolerance);
+        rebalance_due = rebalance_due || out_of_band;
+        weights.push(WeightItem {
+            asset: position.position.asset.address,
+            balance: position.balance,
+            price: position.price,
+            value: position.value,
+            current_weight,
+            target_weight,
+            drift,
+            over_target,
+            rebalance_due: out_of_band,
+        });
+    }
+
+    Ok(QueryAnswer::GetWeights {
+        weights,
+        total_value,
+        rebalance_due,
+        deposit_swaps,
+    })
+}
+
+fn get_nav(deps: Deps, env: Env) -> Result<QueryAnswer, ContractError> {
+    let state = CONFIG.load(deps.storage)?;
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let nav = compute_nav(deps.storage, &deps.querier, &env, &state, &viewing_key)?;
+    Ok(QueryAnswer::GetNav { nav })
+}
+
+fn get_history(
+    deps: Deps,
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+    page: u32,
+    page_size: u32,
+) -> Result<QueryAnswer, ContractError> {
+    let first = first_snapshot_at(deps.storage, start_time.unwrap_or(0))?;
+    let end = match end_time {
+        Some(end_time) if end_time < u64::MAX => first_snapshot_at(deps.storage, end_time + 1)?,
+        _ => NAV_HISTORY.get_len(deps.storage)?,
+    };
+    let total = end.saturating_sub(first);
+    let start = first.saturating_add(page.saturating_mul(page_size.min(MAX_PAGE_SIZE)));
+    let mut snapshots = vec![];
+    for i in start..end.min(start.saturating_add(page_size.min(MAX_PAGE_SIZE))) {
+        snapshots.push(NAV_HISTORY.get_at(deps.storage, i)?);
+    }
+    Ok(QueryAnswer::GetHistory { snapshots, total })
+}
+
+// Index of the first snapshot at or after timestamp, snapshots are appended in time order
+fn first_snapshot_at(storage: &dyn Storage, timestamp: u64) -> Result<u32, ContractError> {
+    let mut low = 0u32;
+    let mut high = NAV_HISTORY.get_len(storage)?;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if NAV_HISTORY.get_at(storage, mid)?.timestamp < timestamp {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    Ok(low)
+}
+
+fn get_fees(deps: Deps) -> Result<QueryAnswer, ContractError> {
+    let state = CONFIG.load(deps.storage)?;
+    let mut fees = vec![];
+    for position in held_assets(deps.storage, &state)? {
         fees.push(Fee {
             asset: position.asset.address.clone(),
             amount: FEES
@@ -419,3 +2929,399 @@ fn get_fees(deps: Deps) -> StdResult<QueryAnswer> {
     }
     Ok(QueryAnswer::GetFees { fees })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Portfolio;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info, MockQuerier};
+    use cosmwasm_std::{ContractResult, SystemError, SystemResult, WasmQuery};
+    use rebalancer_factory::msg::{
+        Price, QueryAnswer as FactoryQueryAnswer, QueryMsg as FactoryQueryMsg,
+    };
+
+    const KEY: &str = "portfolio_key";
+    // Factory price of every asset, 2 USD for one whole token of 6 decimals
+    const PRICE: u128 = 2_000_000_000_000_000_000;
+
+    fn contract_info(address: &str) -> ContractInfo {
+        ContractInfo {
+            address: Addr::unchecked(address),
+            code_hash: format!("{}_hash", address),
+        }
+    }
+
+    fn position(asset: &str, percent: u128) -> PortfolioConfig {
+        PortfolioConfig {
+            percent,
+            asset: contract_info(asset),
+            tolerance: None,
+        }
+    }
+
+    fn one() -> Uint128 {
+        Uint128::new(10).pow(NORMALIZATION_FACTOR)
+    }
+
+    fn setup(mut deps: DepsMut) -> Config {
+        instantiate(
+            deps.branch(),
+            mock_env(),
+            mock_info("creator", &[]),
+            InstantiateMsg {
+                factory: contract_info("factory"),
+                accepted_deposit_tokens: vec![contract_info("usdc")],
+                viewing_key: KEY.to_string(),
+                portfolio: Portfolio {
+                    config: vec![position("atom", 50), position("usdc", 50)],
+                    creator: Addr::unchecked("creator"),
+                    name: "portfolio".to_string(),
+                    snip20: contract_info("share"),
+                },
+                admin: Addr::unchecked("admin"),
+                address_privacy: None,
+                basket_tolerance_percent: None,
+                deposit_mode: None,
+                weight_change_delay: None,
+                governance: None,
+                strategy: None,
+                risk_off: None,
+            },
+        )
+        .unwrap();
+        CONFIG.load(deps.storage).unwrap()
+    }
+
+    // The factory prices every asset at PRICE and routes any pair, the share token has supply
+    // shares of 6 decimals, assets without a balance fail their balance query
+    fn mock_contracts(
+        querier: &mut MockQuerier,
+        balances: Vec<(&'static str, u128)>,
+        supply: u128,
+    ) {
+        querier.update_wasm(move |query| match query {
+            WasmQuery::Smart {
+                contract_addr, msg, ..
+            } => match contract_addr.as_str() {
+                "factory" => SystemResult::Ok(ContractResult::Ok(factory_answer(msg))),
+                token => SystemResult::Ok(token_answer(token, &balances, supply)),
+            },
+            _ => SystemResult::Err(SystemError::UnsupportedRequest {
+                kind: "wasm".to_string(),
+            }),
+        });
+    }
+
+    fn factory_answer(msg: &Binary) -> Binary {
+        match from_binary::<FactoryQueryMsg>(msg).unwrap() {
+            FactoryQueryMsg::Prices { assets, .. } => to_binary(&FactoryQueryAnswer::Prices {
+                prices: assets
+                    .into_iter()
+                    .map(|asset| Price {
+                        asset,
+                        price: Uint128::new(PRICE),
+                        decimals: 6,
+                    })
+                    .collect(),
+            }),
+            FactoryQueryMsg::Route { route, .. } => to_binary(&FactoryQueryAnswer::Route {
+                route: Route {
+                    key: route,
+                    route: vec![],
+                    router_contract: contract_info("router"),
+                    kind: RouteKind::Swap,
+                },
+            }),
+            _ => panic!("unexpected factory query"),
+        }
+        .unwrap()
+    }
+
+    fn token_answer(
+        token: &str,
+        balances: &[(&'static str, u128)],
+        supply: u128,
+    ) -> ContractResult<Binary> {
+        let answer = if token == "share" {
+            format!(
+                concat!(
+                    r#"{{"token_info":{{"name":"share","symbol":"SHARE","#,
+                    r#""decimals":6,"total_supply":"{}"}}}}"#
+                ),
+                supply
+            )
+        } else {
+            match balances.iter().find(|x| x.0 == token) {
+                Some((_, amount)) => format!(r#"{{"balance":{{"amount":"{}"}}}}"#, amount),
+                None => return ContractResult::Err("balance unavailable".to_string()),
+            }
+        };
+        ContractResult::Ok(Binary::from(answer.into_bytes()))
+    }
+
+    fn transfer(recipient: &str, amount: u128, asset: &str) -> CosmosMsg {
+        let asset = contract_info(asset);
+        snip20::transfer_msg(
+            recipient.to_string(),
+            Uint128::new(amount),
+            None,
+            None,
+            BLOCK_SIZE,
+            asset.code_hash,
+            asset.address.into_string(),
+        )
+        .unwrap()
+    }
+
+    fn mint(recipient: &str, amount: u128) -> CosmosMsg {
+        let share = contract_info("share");
+        snip20::mint_msg(
+            recipient.to_string(),
+            Uint128::new(amount),
+            None,
+            None,
+            BLOCK_SIZE,
+            share.code_hash,
+            share.address.into_string(),
+        )
+        .unwrap()
+    }
+
+    fn withdraw(
+        deps: DepsMut,
+        sender: &str,
+        zap_out: Option<ZapOut>,
+    ) -> Result<Response, ContractError> {
+        try_withdraw(
+            deps,
+            mock_env(),
+            mock_info(sender, &[]),
+            one() / Uint128::new(4),
+            Addr::unchecked("user"),
+            1,
+            zap_out,
+        )
+    }
+
+    fn zap_out(asset: &str, min_output: u128) -> Option<ZapOut> {
+        Some(ZapOut {
+            asset: contract_info(asset),
+            min_output: Uint128::new(min_output),
+        })
+    }
+
+    #[test]
+    fn withdraw_pays_out_share_of_each_asset() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        mock_contracts(
+            &mut deps.querier,
+            vec![("atom", 1_000), ("usdc", 2_000)],
+            1_000,
+        );
+
+        let err = withdraw(deps.as_mut(), "user", None).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized(_)));
+        // The admin can't pay out holdings without the factory burning shares
+        let err = withdraw(deps.as_mut(), "admin", None).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized(_)));
+
+        let response = withdraw(deps.as_mut(), "factory", None).unwrap();
+        let messages: Vec<CosmosMsg> = response.messages.into_iter().map(|x| x.msg).collect();
+        assert_eq!(
+            messages,
+            vec![
+                transfer("user", 248, "atom"),
+                transfer("admin", 2, "atom"),
+                transfer("user", 495, "usdc"),
+                transfer("admin", 5, "usdc"),
+            ]
+        );
+        assert_eq!(
+            FEES.get(deps.as_ref().storage, &Addr::unchecked("usdc")),
+            Some(Uint128::new(5))
+        );
+    }
+
+    #[test]
+    fn zap_out_swaps_into_one_asset_and_checks_the_output() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        mock_contracts(
+            &mut deps.querier,
+            vec![("atom", 1_000), ("usdc", 2_000)],
+            1_000,
+        );
+
+        let err = withdraw(deps.as_mut(), "factory", zap_out("osmo", 1)).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidToken(_)));
+
+        let response = withdraw(deps.as_mut(), "factory", zap_out("usdc", 500)).unwrap();
+        // Both fee transfers, then the atom swap whose reply pays out
+        assert_eq!(response.messages.len(), 3);
+        assert_eq!(response.messages[2].id, ZAP_OUT_REPLY_ID);
+        let pending = ZAP_OUT.load(deps.as_ref().storage).unwrap();
+        assert_eq!(pending.direct_amount, Uint128::new(495));
+        assert_eq!(pending.balance_before, Uint128::new(1_995));
+
+        // The swap brought in 5 usdc on top of the 495 withdrawn directly
+        let response = try_finish_zap_out(deps.as_mut(), mock_env()).unwrap();
+        assert_eq!(response.messages[0].msg, transfer("user", 500, "usdc"));
+
+        withdraw(deps.as_mut(), "factory", zap_out("usdc", 501)).unwrap();
+        let err = try_finish_zap_out(deps.as_mut(), mock_env()).unwrap_err();
+        assert!(matches!(err, ContractError::SlippageExceeded(_)));
+    }
+
+    #[test]
+    fn deposits_mint_shares_by_value_added() {
+        let mut deps = mock_dependencies();
+        let config = setup(deps.as_mut());
+        let depositors = vec![
+            DepositShare {
+                depositor: Addr::unchecked("alice"),
+                value: Uint256::from(3u128),
+            },
+            DepositShare {
+                depositor: Addr::unchecked("bob"),
+                value: Uint256::from(1u128),
+            },
+        ];
+
+        // The first deposit is priced at one whole share per 10^18 of value
+        mock_contracts(&mut deps.querier, vec![], 0);
+        let querier = deps.as_ref().querier;
+        let messages = deposit_mint_msgs(
+            &querier,
+            &config,
+            &depositors,
+            Uint256::from_uint128(one() * Uint128::new(4)),
+            Uint256::zero(),
+        )
+        .unwrap();
+        assert_eq!(
+            messages,
+            vec![mint("alice", 3_000_000), mint("bob", 1_000_000)]
+        );
+
+        // Later deposits get the share of the supply their value adds
+        mock_contracts(&mut deps.querier, vec![], 1_000);
+        let querier = deps.as_ref().querier;
+        let messages = deposit_mint_msgs(
+            &querier,
+            &config,
+            &depositors,
+            Uint256::from(2_000u128),
+            Uint256::from(4_000u128),
+        )
+        .unwrap();
+        assert_eq!(messages, vec![mint("alice", 375), mint("bob", 125)]);
+    }
+
+    #[test]
+    fn emergency_withdraw_keeps_failed_assets_as_claims() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        mock_contracts(&mut deps.querier, vec![("usdc", 2_000)], 1_000);
+        let share = one() / Uint128::new(4);
+
+        let response = try_emergency_withdraw(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("factory", &[]),
+            share,
+            Addr::unchecked("user"),
+        )
+        .unwrap();
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(response.messages[0].id, EMERGENCY_TRANSFER_REPLY_ID);
+        assert_eq!(response.messages[0].msg, transfer("user", 500, "usdc"));
+        let atom = Addr::unchecked("atom");
+        assert_eq!(
+            held_balance(deps.as_ref().storage, &atom, Uint128::new(1_000)),
+            Uint128::new(750)
+        );
+
+        // A failed transfer is kept as a claim too
+        try_finish_emergency_transfer(deps.as_mut(), false).unwrap();
+        assert!(EMERGENCY_TRANSFERS
+            .load(deps.as_ref().storage)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            held_balance(
+                deps.as_ref().storage,
+                &Addr::unchecked("usdc"),
+                Uint128::new(2_000)
+            ),
+            Uint128::new(1_500)
+        );
+
+        // A later holder's claim is taken from what is left after the first
+        record_claim(
+            deps.as_mut().storage,
+            &Addr::unchecked("other"),
+            &contract_info("atom"),
+            one() / Uint128::new(5),
+        )
+        .unwrap();
+        assert_eq!(
+            held_balance(deps.as_ref().storage, &atom, Uint128::new(1_000)),
+            Uint128::new(600)
+        );
+
+        // Claims follow the balance, which grew since they were recorded
+        mock_contracts(&mut deps.querier, vec![("atom", 2_000)], 1_000);
+        let response = try_claim_emergency(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("user", &[]),
+            atom.clone(),
+        )
+        .unwrap();
+        assert_eq!(response.messages[0].msg, transfer("user", 500, "atom"));
+        let err = try_claim_emergency(deps.as_mut(), mock_env(), mock_info("user", &[]), atom)
+            .unwrap_err();
+        assert!(matches!(err, ContractError::NotFound(_)));
+    }
+
+    #[test]
+    fn limits_compare_values_in_usd_whatever_the_decimals() {
+        let mut deps = mock_dependencies();
+        let mut config = setup(deps.as_mut());
+        config.limits.min_deposit = Some(Uint256::from_uint128(one()));
+        let depositor = Addr::unchecked("user");
+        let limits = |value| {
+            check_deposit_limits(
+                deps.as_ref().storage,
+                &config,
+                &[],
+                &depositor,
+                value,
+                Uint256::zero(),
+            )
+        };
+
+        // Half a token at 2 USD is 1 USD for 6 and 18 decimals alike
+        let price = Uint128::new(PRICE);
+        limits(token_value(Uint128::new(500_000), price, 6)).unwrap();
+        limits(token_value(one() / Uint128::new(2), price, 18)).unwrap();
+        let err = limits(token_value(Uint128::new(499_999), price, 6)).unwrap_err();
+        assert!(matches!(err, ContractError::LimitExceeded(_)));
+        assert_eq!(
+            token_amount(Uint256::from_uint128(one()), price, 6).unwrap(),
+            Uint256::from(500_000u128)
+        );
+    }
+
+    #[test]
+    fn weights_must_be_unique_and_add_up_to_100() {
+        validate_weights(&[position("atom", 40), position("usdc", 60)]).unwrap();
+        let err = validate_weights(&[position("atom", 40), position("usdc", 50)]).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidWeights(_)));
+        let err = validate_weights(&[position("atom", 50), position("atom", 50)]).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidWeights(_)));
+        let err = validate_weights(&[]).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidWeights(_)));
+    }
+}
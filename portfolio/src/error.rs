@@ -0,0 +1,73 @@
+use cosmwasm_std::{ConversionOverflowError, DivideByZeroError, OverflowError, StdError};
+use thiserror::Error;
+
+// Every message starts with a stable code clients can match on, codes are unique and never
+// reused for a different meaning, the factory contract has its own F codes
+#[derive(Error, Debug, PartialEq)]
+pub enum ContractError {
+    #[error("P00 {0}")]
+    Std(#[from] StdError),
+    #[error("P01 Math error: {0}")]
+    Overflow(#[from] OverflowError),
+    #[error("P02 Math error: {0}")]
+    DivideByZero(#[from] DivideByZeroError),
+    #[error("P03 Math error: {0}")]
+    ConversionOverflow(#[from] ConversionOverflowError),
+    #[error("P10 Unauthorized: must be {0}")]
+    Unauthorized(String),
+    #[error("P20 Invalid weights: {0}")]
+    InvalidWeights(String),
+    #[error("P21 Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("P22 Invalid token: {0}")]
+    InvalidToken(String),
+    #[error("P23 Invalid amount: {0}")]
+    InvalidAmount(String),
+    #[error("P30 Stale price: no current price for {0}")]
+    StalePrice(String),
+    #[error("P32 Slippage exceeded: {0}")]
+    SlippageExceeded(String),
+    #[error("P40 Not found: {0}")]
+    NotFound(String),
+    #[error("P50 Invalid viewing key")]
+    InvalidViewingKey,
+    #[error("P51 Query requires a permit")]
+    PermitRequired,
+    #[error("P52 Access denied: {0}")]
+    AccessDenied(String),
+    #[error("P60 Limit exceeded: {0}")]
+    LimitExceeded(String),
+    #[error("P61 Assets held for users cannot be rescued")]
+    RescueForbidden,
+    #[error("P70 Governance: {0}")]
+    Governance(String),
+    #[error("P80 Unknown reply id {0}")]
+    UnknownReply(u64),
+}
+
+impl ContractError {
+    // The code the message starts with
+    pub fn code(&self) -> &'static str {
+        match self {
+            ContractError::Std(_) => "P00",
+            ContractError::Overflow(_) => "P01",
+            ContractError::DivideByZero(_) => "P02",
+            ContractError::ConversionOverflow(_) => "P03",
+            ContractError::Unauthorized(_) => "P10",
+            ContractError::InvalidWeights(_) => "P20",
+            ContractError::InvalidConfig(_) => "P21",
+            ContractError::InvalidToken(_) => "P22",
+            ContractError::InvalidAmount(_) => "P23",
+            ContractError::StalePrice(_) => "P30",
+            ContractError::SlippageExceeded(_) => "P32",
+            ContractError::NotFound(_) => "P40",
+            ContractError::InvalidViewingKey => "P50",
+            ContractError::PermitRequired => "P51",
+            ContractError::AccessDenied(_) => "P52",
+            ContractError::LimitExceeded(_) => "P60",
+            ContractError::RescueForbidden => "P61",
+            ContractError::Governance(_) => "P70",
+            ContractError::UnknownReply(_) => "P80",
+        }
+    }
+}
@@ -1,3 +1,5 @@
 pub mod contract;
+pub mod error;
 pub mod msg;
 pub mod state;
+pub mod strategy;
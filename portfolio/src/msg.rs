@@ -1,7 +1,13 @@
-use crate::state::{Config, Portfolio, PortfolioConfig};
+use crate::state::{
+    Config, DepositMode, GovernanceConfig, NavSnapshot, PendingWeights, Portfolio, PortfolioConfig,
+    Proposal, ProposalAction, RiskOffConfig, VoteOption,
+};
+use crate::strategy::WeightingStrategy;
 use cosmwasm_schema::cw_serde;
 use cosmwasm_std::{Addr, Binary, ContractInfo, Uint128, Uint256};
-use rebalancer_factory::state::SwapContract;
+use rebalancer_factory::msg::{Capacity, Nav, WeightItem, ZapOut};
+use rebalancer_factory::state::{AddressPrivacy, PortfolioLimits, SwapContract};
+use secret_toolkit::permit::Permit;
 
 #[cw_serde]
 pub struct InstantiateMsg {
@@ -10,6 +16,13 @@ pub struct InstantiateMsg {
     pub viewing_key: String,
     pub portfolio: Portfolio,
     pub admin: Addr,
+    pub address_privacy: Option<AddressPrivacy>,
+    pub basket_tolerance_percent: Option<u128>,
+    pub deposit_mode: Option<DepositMode>,
+    pub weight_change_delay: Option<u64>,
+    pub governance: Option<GovernanceConfig>,
+    pub strategy: Option<WeightingStrategy>,
+    pub risk_off: Option<RiskOffConfig>,
 }
 
 #[cw_serde]
@@ -24,6 +37,77 @@ pub enum ExecuteMsg {
         share: Uint128,
         receiver: Addr,
         fee: u128,
+        #[serde(default)]
+        zap_out: Option<ZapOut>,
+    },
+    // Raw pro-rata payout with no prices or routes, sent while the factory is PROTECTED
+    EmergencyWithdraw {
+        share: Uint128,
+        receiver: Addr,
+    },
+    // Pays out what an emergency withdrawal skipped, once the asset works again
+    ClaimEmergency {
+        asset: Addr,
+    },
+    // Share tokens are minted by the portfolio
+    DepositBasket {
+        depositor: Addr,
+        min_shares: Option<Uint128>,
+    },
+    CancelBasket {
+        depositor: Addr,
+    },
+    // CREATOR
+    // Replaces the target weights after the weight change delay
+    // Assets left out are sold down to nothing on the following Updates
+    ProposeWeights {
+        config: Vec<PortfolioConfig>,
+    },
+    CancelWeights {},
+    // CREATOR OR ADMIN
+    // Sets a viewing key so the stray token's balance can be listed
+    RegisterStrayToken {
+        token: ContractInfo,
+    },
+    // Swaps a registered stray token into the allocation for holders, the whole balance
+    // when amount is not set, sweep sends it to the admin instead
+    RescueTokens {
+        token: Addr,
+        amount: Option<Uint128>,
+        #[serde(default)]
+        sweep: bool,
+    },
+    // Moves the portfolio into the safe asset on the next Update, clearing restores the targets
+    SetRiskOff {
+        active: bool,
+    },
+    // SHARE HOLDERS
+    // Shares are locked for voting by sending them with ReceiveMsg::LockShares
+    UnlockShares {
+        amount: Uint128,
+    },
+    CreateProposal {
+        action: ProposalAction,
+    },
+    CastVote {
+        proposal_id: u32,
+        vote: VoteOption,
+    },
+    // Applies or rejects a proposal once voting has ended, anyone can call
+    ExecuteProposal {
+        proposal_id: u32,
+    },
+    // FACTORY
+    // Closes balance and NAV queries to anyone without a permit
+    SetPermitQueries {
+        enabled: bool,
+    },
+    SetLimits {
+        limits: PortfolioLimits,
+    },
+    // PERMITS
+    RevokePermit {
+        permit_name: String,
     },
 
     //Receiver interface
@@ -36,12 +120,80 @@ pub enum ExecuteMsg {
     },
 }
 
+#[cw_serde]
+pub enum ReceiveMsg {
+    // Swaps an accepted deposit token into the target allocation and mints shares
+    Deposit { depositor: Addr },
+    // Holds a portfolio asset for an in-kind deposit until DepositBasket
+    StageBasket { depositor: Addr },
+    // Sent by share holders, locked shares carry voting power
+    LockShares {},
+}
+
 #[cw_serde]
 pub enum QueryMsg {
     // GetCount returns the current count as a json-encoded number
     GetConfig {},
     GetFees {},
     GetBalances {},
+    // Positions without their own band are checked against tolerance_percent, 0 if not set
+    // key is the portfolio viewing key, needed when queries require a permit
+    GetWeights {
+        tolerance_percent: Option<u128>,
+        #[serde(default)]
+        key: Option<String>,
+    },
+    // key is the portfolio viewing key, needed when queries require a permit
+    GetNav {
+        #[serde(default)]
+        key: Option<String>,
+    },
+    GetDepositTokens {},
+    GetPendingWeights {},
+    GetRiskOff {},
+    GetStrayTokens {},
+    GetProposal {
+        proposal_id: u32,
+    },
+    GetProposals {
+        page: u32,
+        page_size: u32,
+    },
+    // Snapshots between start_time and end_time inclusive, in seconds, at most 100 per page
+    GetHistory {
+        start_time: Option<u64>,
+        end_time: Option<u64>,
+        page: u32,
+        page_size: u32,
+    },
+    // key is the portfolio viewing key, needed with address or when queries require a permit
+    GetCapacity {
+        address: Option<Addr>,
+        key: Option<String>,
+    },
+    WithPermit {
+        permit: Permit,
+        query: QueryWithPermit,
+    },
+}
+
+// Queries closed by SetPermitQueries, answered the same as their plain versions
+#[cw_serde]
+pub enum QueryWithPermit {
+    GetBalances {},
+    GetWeights {
+        tolerance_percent: Option<u128>,
+    },
+    GetNav {},
+    GetRiskOff {},
+    // Remaining capacity for the permit signer
+    GetCapacity {},
+    GetHistory {
+        start_time: Option<u64>,
+        end_time: Option<u64>,
+        page: u32,
+        page_size: u32,
+    },
 }
 
 #[cw_serde]
@@ -58,9 +210,52 @@ pub struct BalanceItem {
 
 #[cw_serde]
 pub enum QueryAnswer {
-    GetConfig { config: Config },
-    GetFees { fees: Vec<Fee> },
-    GetBalances { balances: Vec<BalanceItem> },
+    GetConfig {
+        config: Config,
+    },
+    GetFees {
+        fees: Vec<Fee>,
+    },
+    GetBalances {
+        balances: Vec<BalanceItem>,
+    },
+    GetWeights {
+        weights: Vec<WeightItem>,
+        total_value: Uint256,
+        rebalance_due: bool,
+        // Swaps the next Update makes to bring queued deposits in
+        deposit_swaps: u32,
+    },
+    GetNav {
+        nav: Nav,
+    },
+    GetDepositTokens {
+        deposit_tokens: Vec<ContractInfo>,
+    },
+    GetPendingWeights {
+        pending: Option<PendingWeights>,
+    },
+    GetRiskOff {
+        active: bool,
+        peak_nav_per_share: Option<Uint256>,
+    },
+    GetProposal {
+        proposal: Proposal,
+    },
+    GetProposals {
+        proposals: Vec<Proposal>,
+        total: u32,
+    },
+    GetHistory {
+        snapshots: Vec<NavSnapshot>,
+        total: u32,
+    },
+    GetCapacity {
+        capacity: Capacity,
+    },
+    GetStrayTokens {
+        balances: Vec<BalanceItem>,
+    },
 }
 
 #[cw_serde]
@@ -89,14 +284,19 @@ pub struct WithdrawAction {
 
 pub struct PositionDetails {
     pub position: PortfolioConfig,
+    pub balance: Uint128,
+    // 18 decimal USD, the same unit for every asset
     pub value: Uint256,
+    // 18 decimal USD for one whole token
     pub price: Uint128,
+    pub decimals: u8,
 }
 
 pub struct PositionCorrection {
     pub position: PortfolioConfig,
     pub correction: Uint256,
     pub price: Uint128,
+    pub decimals: u8,
 }
 
 #[cw_serde]
@@ -1,6 +1,10 @@
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, ContractInfo, Uint128};
-use secret_toolkit::storage::{Item, Keymap};
+use cosmwasm_std::{Addr, ContractInfo, Uint128, Uint256};
+use rebalancer_factory::state::{AddressPrivacy, PortfolioLimits, ToleranceBand};
+use secret_toolkit::storage::{AppendStore, Item, Keymap};
+
+use crate::contract::{DEFAULT_BASKET_TOLERANCE_PERCENT, DEFAULT_WEIGHT_CHANGE_DELAY};
+use crate::strategy::WeightingStrategy;
 
 pub static CONFIG_KEY: &[u8] = b"config";
 
@@ -8,14 +12,168 @@ pub static CONFIG_KEY: &[u8] = b"config";
 pub struct Config {
     pub factory: ContractInfo,
     pub admin: Addr,
-    pub accepted_deposit_tokens: Vec<ContractInfo>,
     pub portfolio: Portfolio,
+    #[serde(default)]
+    pub address_privacy: AddressPrivacy,
+    // Allowed distance between basket weights and portfolio weights for in-kind deposits
+    #[serde(default = "default_basket_tolerance_percent")]
+    pub basket_tolerance_percent: u128,
+    #[serde(default)]
+    pub deposit_mode: DepositMode,
+    // Seconds between a weight change being proposed and taking effect
+    #[serde(default = "default_weight_change_delay")]
+    pub weight_change_delay: u64,
+    #[serde(default)]
+    pub strategy: WeightingStrategy,
+    // Manual and drawdown switch into a safe asset, off when not set
+    pub risk_off: Option<RiskOffConfig>,
+    // Share holder voting, proposals are rejected when not set
+    pub governance: Option<GovernanceConfig>,
+    // Set by governance, overrides the fee sent by the factory on Withdraw
+    pub withdraw_fee: Option<u128>,
+    // Set by governance, overrides the tolerance sent by the factory on Update
+    pub tolerance_percent: Option<u128>,
+    // Set by the factory, balance and NAV queries then need a permit from an allowed address
+    #[serde(default)]
+    pub permit_queries: bool,
+    // Set by the factory, mirrors the creator's limits there
+    #[serde(default)]
+    pub limits: PortfolioLimits,
+}
+
+fn default_basket_tolerance_percent() -> u128 {
+    DEFAULT_BASKET_TOLERANCE_PERCENT
+}
+
+fn default_weight_change_delay() -> u64 {
+    DEFAULT_WEIGHT_CHANGE_DELAY
+}
+
+#[cw_serde]
+pub struct RiskOffConfig {
+    // Held at 100% while risk-off is on, must be a portfolio asset or deposit token
+    pub safe_asset: ContractInfo,
+    // Drop from the peak share price that turns risk-off on, manual only when not set
+    pub drawdown_percent: Option<u128>,
+}
+
+#[cw_serde]
+pub struct GovernanceConfig {
+    // Percent of locked shares at the snapshot that must vote
+    pub quorum_percent: u128,
+    // Percent of yes out of yes and no votes needed to pass
+    pub threshold_percent: u128,
+    // Seconds a proposal is open for votes
+    pub voting_period: u64,
+    // Locked shares needed to create a proposal
+    pub proposal_min_shares: Uint128,
+}
+
+#[cw_serde]
+pub enum ProposalAction {
+    SetWeights { config: Vec<PortfolioConfig> },
+    SetWithdrawFee { fee: u128 },
+    SetTolerance { tolerance_percent: u128 },
+}
+
+#[cw_serde]
+pub enum ProposalStatus {
+    Open,
+    Rejected,
+    Executed,
+}
+
+#[cw_serde]
+pub enum VoteOption {
+    Yes,
+    No,
+    Abstain,
+}
+
+#[cw_serde]
+pub struct Proposal {
+    pub id: u32,
+    pub action: ProposalAction,
+    // Votes are weighted by locked shares before this height
+    pub snapshot_height: u64,
+    pub end_time: u64,
+    pub yes: Uint128,
+    pub no: Uint128,
+    pub abstain: Uint128,
+    // Locked shares at the snapshot, quorum is measured against it
+    pub total_power: Uint128,
+    pub status: ProposalStatus,
+}
+
+// Locked share amount from height onwards
+#[cw_serde]
+pub struct Checkpoint {
+    pub height: u64,
+    pub amount: Uint128,
+}
+
+// New targets waiting out the delay, holders can exit before they apply
+#[cw_serde]
+pub struct PendingWeights {
+    pub config: Vec<PortfolioConfig>,
+    pub effective_at: u64,
+}
+
+// When single token deposits are swapped into the target allocation
+#[cw_serde]
+#[derive(Default)]
+pub enum DepositMode {
+    #[default]
+    Immediate,
+    // Held until the next Update
+    Queued,
+}
+
+#[cw_serde]
+pub struct QueuedDeposit {
+    pub depositor: Addr,
+    pub asset: ContractInfo,
+    pub amount: Uint128,
+}
+
+#[cw_serde]
+pub struct DepositShare {
+    pub depositor: Addr,
+    pub value: Uint256,
+}
+
+// Part of an asset's balance owed to emergency withdrawals that could not pay it out
+#[cw_serde]
+pub struct ClaimPool {
+    pub asset: ContractInfo,
+    // Reserved part of the balance left after staged and queued deposits, 18 decimals
+    pub fraction: Uint128,
+    // Sum of every receiver's claim units
+    pub units: Uint128,
+}
+
+// Emergency transfer waiting on its reply, a failure becomes a claim
+#[cw_serde]
+pub struct EmergencyTransfer {
+    pub receiver: Addr,
+    pub asset: ContractInfo,
+    pub share: Uint128,
+}
+
+// Deposits waiting on their swaps to settle, shares are priced from the change in value
+#[cw_serde]
+pub struct PendingDeposit {
+    pub depositors: Vec<DepositShare>,
+    pub value_before: Uint256,
 }
 
 #[cw_serde]
 pub struct PortfolioConfig {
     pub percent: u128,
     pub asset: ContractInfo,
+    // Falls back to the tolerance passed on Update when not set
+    #[serde(default)]
+    pub tolerance: Option<ToleranceBand>,
 }
 
 #[cw_serde]
@@ -26,9 +184,95 @@ pub struct Portfolio {
     pub snip20: ContractInfo,
 }
 
+#[cw_serde]
+pub struct AssetSnapshot {
+    pub asset: Addr,
+    pub balance: Uint128,
+    pub price: Uint128,
+}
+
+#[cw_serde]
+pub struct NavSnapshot {
+    pub timestamp: u64,
+    pub total_value: Uint256,
+    pub nav_per_share: Uint256,
+    pub assets: Vec<AssetSnapshot>,
+}
+
+// Withdrawal waiting on its swaps to settle before the single transfer
+#[cw_serde]
+pub struct PendingZapOut {
+    pub receiver: Addr,
+    pub asset: ContractInfo,
+    pub min_output: Uint128,
+    // Slice already held in the output asset
+    pub direct_amount: Uint128,
+    pub balance_before: Uint128,
+}
+
 pub const KEY_CONFIG: &[u8] = b"config";
 pub const KEY_FEES: &[u8] = b"fees";
 pub const KEY_VIEWING_KEY: &[u8] = b"viewing_key";
+pub const KEY_NAV_HISTORY: &[u8] = b"nav_history";
+pub const KEY_STAGED_BASKETS: &[u8] = b"staged_baskets";
+pub const KEY_STAGED_TOTALS: &[u8] = b"staged_totals";
+pub const KEY_ZAP_OUT: &[u8] = b"zap_out";
+pub const KEY_DEPOSIT_TOKENS: &[u8] = b"deposit_tokens";
+pub const KEY_PENDING_WEIGHTS: &[u8] = b"pending_weights";
+pub const KEY_RISK_OFF: &[u8] = b"risk_off";
+pub const KEY_PEAK_NAV: &[u8] = b"peak_nav";
+pub const KEY_LOCKED_SHARES: &[u8] = b"locked_shares";
+pub const KEY_SHARE_CHECKPOINTS: &[u8] = b"share_checkpoints";
+pub const KEY_TOTAL_CHECKPOINTS: &[u8] = b"total_checkpoints";
+pub const KEY_PROPOSALS: &[u8] = b"proposals";
+pub const KEY_VOTES: &[u8] = b"votes";
+pub const KEY_QUEUED_DEPOSITS: &[u8] = b"queued_deposits";
+pub const KEY_QUEUED_TOTALS: &[u8] = b"queued_totals";
+pub const KEY_PENDING_DEPOSIT: &[u8] = b"pending_deposit";
+pub const KEY_USER_DEPOSITS: &[u8] = b"user_deposits";
+pub const KEY_STRAY_TOKENS: &[u8] = b"stray_tokens";
+pub const KEY_CLAIM_POOLS: &[u8] = b"claim_pools";
+pub const KEY_CLAIMS: &[u8] = b"claims";
+pub const KEY_EMERGENCY_TRANSFERS: &[u8] = b"emergency_transfers";
+pub const KEY_UPDATE_SNAPSHOTS: &[u8] = b"update_snapshots";
+pub const PREFIX_REVOKED_PERMITS: &str = "revoked_permits";
 pub static CONFIG: Item<Config> = Item::new(KEY_CONFIG);
 pub static FEES: Keymap<Addr, Uint128> = Keymap::new(KEY_FEES);
 pub static VIEWING_KEY: Item<String> = Item::new(KEY_VIEWING_KEY);
+// Snapshot taken on every update, deposit and withdraw, oldest first
+pub static NAV_HISTORY: AppendStore<NavSnapshot> = AppendStore::new(KEY_NAV_HISTORY);
+// Positions in NAV_HISTORY of the snapshots taken by updates, oldest first
+pub static UPDATE_SNAPSHOTS: AppendStore<u32> = AppendStore::new(KEY_UPDATE_SNAPSHOTS);
+// Basket deposit amounts per asset, suffixed by depositor address
+pub static STAGED_BASKETS: Keymap<Addr, Uint128> = Keymap::new(KEY_STAGED_BASKETS);
+// Sum of staged amounts per asset, excluded from the portfolio's own holdings
+pub static STAGED_TOTALS: Keymap<Addr, Uint128> = Keymap::new(KEY_STAGED_TOTALS);
+pub static ZAP_OUT: Item<PendingZapOut> = Item::new(KEY_ZAP_OUT);
+// Tokens accepted for single token deposits, held at 0% unless also in the allocation
+pub static DEPOSIT_TOKENS: Item<Vec<ContractInfo>> = Item::new(KEY_DEPOSIT_TOKENS);
+pub static PENDING_WEIGHTS: Item<PendingWeights> = Item::new(KEY_PENDING_WEIGHTS);
+pub static RISK_OFF: Item<bool> = Item::new(KEY_RISK_OFF);
+// Highest share price in the snapshots since risk-off was last cleared
+pub static PEAK_NAV: Item<Uint256> = Item::new(KEY_PEAK_NAV);
+pub static LOCKED_SHARES: Keymap<Addr, Uint128> = Keymap::new(KEY_LOCKED_SHARES);
+// Locked share history, suffixed by holder
+pub static SHARE_CHECKPOINTS: AppendStore<Checkpoint> = AppendStore::new(KEY_SHARE_CHECKPOINTS);
+pub static TOTAL_CHECKPOINTS: AppendStore<Checkpoint> = AppendStore::new(KEY_TOTAL_CHECKPOINTS);
+// Proposal ids are their position in the store
+pub static PROPOSALS: AppendStore<Proposal> = AppendStore::new(KEY_PROPOSALS);
+// Votes cast, suffixed by proposal id
+pub static VOTES: Keymap<Addr, VoteOption> = Keymap::new(KEY_VOTES);
+pub static QUEUED_DEPOSITS: Item<Vec<QueuedDeposit>> = Item::new(KEY_QUEUED_DEPOSITS);
+// Sum of queued amounts per asset, excluded from the portfolio's own holdings
+pub static QUEUED_TOTALS: Keymap<Addr, Uint128> = Keymap::new(KEY_QUEUED_TOTALS);
+pub static PENDING_DEPOSIT: Item<PendingDeposit> = Item::new(KEY_PENDING_DEPOSIT);
+// Net value deposited by each address, checked against the user cap
+pub static USER_DEPOSITS: Keymap<Addr, Uint256> = Keymap::new(KEY_USER_DEPOSITS);
+// Keyed by asset, excluded from the portfolio's own holdings
+pub static CLAIM_POOLS: Keymap<Addr, ClaimPool> = Keymap::new(KEY_CLAIM_POOLS);
+// Claim units per asset, suffixed by receiver
+pub static CLAIMS: Keymap<Addr, Uint128> = Keymap::new(KEY_CLAIMS);
+// Replies arrive in the order the transfers were sent
+pub static EMERGENCY_TRANSFERS: Item<Vec<EmergencyTransfer>> = Item::new(KEY_EMERGENCY_TRANSFERS);
+// Tokens outside the portfolio sent here by mistake or airdropped
+pub static STRAY_TOKENS: Keymap<Addr, ContractInfo> = Keymap::new(KEY_STRAY_TOKENS);
@@ -0,0 +1,313 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{QuerierWrapper, StdResult, Storage, Uint128, Uint256};
+use rebalancer_factory::msg::query_supplies;
+
+use crate::contract::{token_value, NORMALIZATION_FACTOR};
+use crate::msg::PositionDetails;
+use crate::state::{Config, NAV_HISTORY, RISK_OFF, UPDATE_SNAPSHOTS};
+
+// How target weights are set at each Update
+#[cw_serde]
+#[derive(Default)]
+pub enum WeightingStrategy {
+    // Percentages from the portfolio config
+    #[default]
+    Static,
+    EqualWeight,
+    // Weighted by price times token supply
+    MarketCap,
+    // Market cap with no asset above cap_percent, the excess goes to the rest
+    CappedMarketCap {
+        cap_percent: u128,
+    },
+    // Weighted by one over the volatility of the returns over the last lookback updates
+    InverseVolatility {
+        lookback: u32,
+    },
+    // Equal weight across the top_n assets by return over the last lookback updates
+    Momentum {
+        lookback: u32,
+        top_n: u32,
+    },
+}
+
+// Replaces position targets with the strategy's weights
+// Assets at 0% in the config are outside the strategy, they are being exited
+pub fn apply_strategy(
+    storage: &dyn Storage,
+    querier: &QuerierWrapper,
+    config: &Config,
+    positions: &mut [PositionDetails],
+) -> StdResult<()> {
+    let universe: Vec<usize> = positions
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| x.position.percent != 0)
+        .map(|(i, _)| i)
+        .collect();
+    if universe.is_empty() {
+        return Ok(());
+    }
+
+    let scores = match &config.strategy {
+        WeightingStrategy::Static => return Ok(()),
+        WeightingStrategy::EqualWeight => Some(vec![Uint256::from(1u128); universe.len()]),
+        WeightingStrategy::MarketCap => Some(market_caps(querier, config, positions, &universe)?),
+        WeightingStrategy::CappedMarketCap { cap_percent } => {
+            let caps = market_caps(querier, config, positions, &universe)?;
+            Some(cap_scores(&caps, *cap_percent))
+        }
+        WeightingStrategy::InverseVolatility { lookback } => {
+            inverse_volatilities(storage, positions, &universe, *lookback)?
+        }
+        WeightingStrategy::Momentum { lookback, top_n } => {
+            momentum_scores(storage, positions, &universe, *lookback, *top_n)?
+        }
+    };
+
+    // Static percentages stay when the strategy has nothing to go on
+    if let Some(percents) = scores.and_then(|scores| percents_from_scores(&scores)) {
+        for (i, percent) in universe.iter().zip(percents) {
+            positions[*i].position.percent = percent;
+        }
+    }
+    Ok(())
+}
+
+// While risk-off is on the safe asset is the only target, applied after the strategy
+pub fn apply_risk_off(
+    storage: &dyn Storage,
+    config: &Config,
+    positions: &mut [PositionDetails],
+) -> StdResult<bool> {
+    let risk_off = match &config.risk_off {
+        Some(risk_off) => risk_off,
+        None => return Ok(false),
+    };
+    if !RISK_OFF.may_load(storage)?.unwrap_or(false) {
+        return Ok(false);
+    }
+    //TODO remove constants
+    for position in positions.iter_mut() {
+        position.position.percent =
+            if position.position.asset.address == risk_off.safe_asset.address {
+                100
+            } else {
+                0
+            };
+    }
+    Ok(true)
+}
+
+fn market_caps(
+    querier: &QuerierWrapper,
+    config: &Config,
+    positions: &[PositionDetails],
+    universe: &[usize],
+) -> StdResult<Vec<Uint256>> {
+    let supplies = query_supplies(
+        querier,
+        config.factory.clone(),
+        universe
+            .iter()
+            .map(|i| positions[*i].position.asset.clone())
+            .collect(),
+    )?;
+    Ok(universe
+        .iter()
+        .map(|i| {
+            let position = &positions[*i];
+            supplies
+                .iter()
+                .find(|x| x.asset == position.position.asset.address)
+                .map(|x| token_value(x.total_supply, position.price, position.decimals))
+                .unwrap_or(Uint256::zero())
+        })
+        .collect())
+}
+
+// Scores scaled so none is above cap_percent of the total, excess is spread by score
+// Falls back to equal scores when the cap cannot cover every asset
+fn cap_scores(scores: &[Uint256], cap_percent: u128) -> Vec<Uint256> {
+    //TODO remove constants
+    if cap_percent.saturating_mul(scores.len() as u128) < 100 {
+        return vec![Uint256::from(1u128); scores.len()];
+    }
+    let total_weight =
+        Uint256::from(100u128).saturating_mul(Uint256::from(10u128).pow(NORMALIZATION_FACTOR));
+    let cap =
+        Uint256::from(cap_percent).saturating_mul(Uint256::from(10u128).pow(NORMALIZATION_FACTOR));
+    let mut weights = vec![Uint256::zero(); scores.len()];
+    let mut capped = vec![false; scores.len()];
+    loop {
+        let capped_weight =
+            cap.saturating_mul(Uint256::from(capped.iter().filter(|x| **x).count() as u128));
+        let remaining = total_weight.saturating_sub(capped_weight);
+        let uncapped_score = scores
+            .iter()
+            .zip(&capped)
+            .filter(|(_, capped)| !**capped)
+            .fold(Uint256::zero(), |acc, (score, _)| {
+                acc.saturating_add(*score)
+            });
+        if uncapped_score.is_zero() {
+            break;
+        }
+        let mut newly_capped = false;
+        for (i, score) in scores.iter().enumerate() {
+            if capped[i] {
+                weights[i] = cap;
+                continue;
+            }
+            weights[i] = remaining.multiply_ratio(*score, uncapped_score);
+            if weights[i] > cap {
+                weights[i] = cap;
+                capped[i] = true;
+                newly_capped = true;
+            }
+        }
+        if !newly_capped {
+            break;
+        }
+    }
+    weights
+}
+
+// One over the standard deviation of each asset's returns between snapshots
+// None when any asset is missing prices in the window
+fn inverse_volatilities(
+    storage: &dyn Storage,
+    positions: &[PositionDetails],
+    universe: &[usize],
+    lookback: u32,
+) -> StdResult<Option<Vec<Uint256>>> {
+    let factor = Uint256::from(10u128).pow(NORMALIZATION_FACTOR);
+    let mut scores = vec![];
+    for i in universe {
+        let prices = price_history(storage, &positions[*i], lookback)?;
+        if prices.len() < 2 {
+            return Ok(None);
+        }
+        let returns: Vec<Uint256> = prices
+            .windows(2)
+            .map(|x| x[1].multiply_ratio(factor, x[0]))
+            .collect();
+        let count = Uint256::from(returns.len() as u128);
+        let mean = returns
+            .iter()
+            .fold(Uint256::zero(), |acc, x| acc.saturating_add(*x))
+            .checked_div(count)?;
+        let variance = returns
+            .iter()
+            .map(|x| {
+                let deviation = if *x > mean { *x - mean } else { mean - *x };
+                deviation.saturating_mul(deviation)
+            })
+            .fold(Uint256::zero(), |acc, x| acc.saturating_add(x))
+            .checked_div(count)?;
+        // Flat prices would get the whole portfolio, floor at the smallest step
+        let volatility = isqrt(variance).max(Uint256::from(1u128));
+        scores.push(factor.saturating_mul(factor).checked_div(volatility)?);
+    }
+    Ok(Some(scores))
+}
+
+// Scores of 1 for the top_n trailing returns and 0 for the rest, earlier assets win ties
+// None when any asset is missing prices in the window
+fn momentum_scores(
+    storage: &dyn Storage,
+    positions: &[PositionDetails],
+    universe: &[usize],
+    lookback: u32,
+    top_n: u32,
+) -> StdResult<Option<Vec<Uint256>>> {
+    let factor = Uint256::from(10u128).pow(NORMALIZATION_FACTOR);
+    let mut returns = vec![];
+    for (rank, i) in universe.iter().enumerate() {
+        let prices = price_history(storage, &positions[*i], lookback)?;
+        match (prices.first(), prices.last()) {
+            (Some(first), Some(last)) if prices.len() >= 2 => {
+                returns.push((last.multiply_ratio(factor, *first), rank))
+            }
+            _ => return Ok(None),
+        }
+    }
+    returns.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    let mut scores = vec![Uint256::zero(); universe.len()];
+    for (_, rank) in returns.iter().take(top_n.max(1) as usize) {
+        scores[*rank] = Uint256::from(1u128);
+    }
+    Ok(Some(scores))
+}
+
+// Prices of a position at the last lookback updates plus the current price, oldest first
+// Deposit and withdraw snapshots are skipped so the window doesn't shrink with user activity
+pub fn price_history(
+    storage: &dyn Storage,
+    position: &PositionDetails,
+    lookback: u32,
+) -> StdResult<Vec<Uint256>> {
+    let len = UPDATE_SNAPSHOTS.get_len(storage)?;
+    let mut prices = vec![];
+    for i in len.saturating_sub(lookback)..len {
+        let snapshot = NAV_HISTORY.get_at(storage, UPDATE_SNAPSHOTS.get_at(storage, i)?)?;
+        if let Some(asset) = snapshot
+            .assets
+            .iter()
+            .find(|x| x.asset == position.position.asset.address)
+        {
+            if !asset.price.is_zero() {
+                prices.push(Uint256::from_uint128(asset.price));
+            }
+        }
+    }
+    if !position.price.is_zero() {
+        prices.push(Uint256::from_uint128(position.price));
+    }
+    Ok(prices)
+}
+
+// Whole percentages proportional to scores that add up to 100, remainders go to the largest
+pub fn percents_from_scores(scores: &[Uint256]) -> Option<Vec<u128>> {
+    let total = scores
+        .iter()
+        .fold(Uint256::zero(), |acc, x| acc.saturating_add(*x));
+    if total.is_zero() {
+        return None;
+    }
+    //TODO remove constants
+    let mut percents = vec![];
+    let mut remainders = vec![];
+    for (i, score) in scores.iter().enumerate() {
+        let scaled = score.saturating_mul(Uint256::from(100u128));
+        let percent = Uint128::try_from(scaled.checked_div(total).ok()?)
+            .ok()?
+            .u128();
+        percents.push(percent);
+        remainders.push((scaled.checked_rem(total).ok()?, i));
+    }
+    remainders.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    let assigned: u128 = percents.iter().sum();
+    for (_, i) in remainders
+        .iter()
+        .take(100u128.saturating_sub(assigned) as usize)
+    {
+        percents[*i] += 1;
+    }
+    Some(percents)
+}
+
+pub fn isqrt(value: Uint256) -> Uint256 {
+    if value.is_zero() {
+        return value;
+    }
+    let two = Uint256::from(2u128);
+    let mut x = value;
+    let mut y = value / two + value % two;
+    while y < x {
+        x = y;
+        y = (x + value / x) / two;
+    }
+    x
+}
@@ -1,21 +1,54 @@
 use cosmwasm_std::{
-    entry_point, from_binary, to_binary, Addr, Binary, ContractInfo, Deps, DepsMut, Env,
-    MessageInfo, Response, StdError, StdResult, Uint128, Uint256,
+    entry_point, from_binary, to_binary, Addr, Binary, ContractInfo, CosmosMsg, Decimal256, Deps,
+    DepsMut, Env, Event, MessageInfo, QuerierWrapper, Reply, Response, StdResult, Storage, SubMsg,
+    SubMsgResult, Uint128, Uint256, WasmMsg,
 };
+use secret_toolkit::snip20;
+use secret_toolkit::viewing_key::{ViewingKey, ViewingKeyStore};
 
-use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, ReceiveMsg};
+use crate::error::ContractError;
+use crate::events::{add_address_attribute, add_event_address};
+use crate::msg::{
+    portfolio_execute_msg, query_portfolio_capacity, query_portfolio_nav, query_portfolio_weights,
+    ExecuteMsg, InstantiateMsg, KeeperInfo, PortfolioDepositTokens, PortfolioExecuteMsg,
+    PortfolioReceiveMsg, Price, QueryAnswer, QueryMsg, RebalanceCandidate, ReceiveMsg, Route,
+    RouteKind, SetPrice, Supply, TokenBalance, WeightItem, ZapOut,
+};
 use crate::state::{
-    Config, ContractStatus, Portfolio, PortfolioConfig, CONFIG, PORTFOLIO_LIST, REGISTERED_ASSETS,
-    UNUPDATED_LIST, VIEWING_KEY,
+    AddressPrivacy, AssetPrice, Config, ContractStatus, DcaPlan, KeeperReward, LimitBounds,
+    Portfolio, PortfolioAccess, PortfolioConfig, PortfolioLimits, QueueRelease, QueuedWithdraw,
+    RewardEpoch, RouteKey, SwapContract, UpdateBatch, UpdatePriority, WithdrawQueueConfig,
+    WithdrawWindow, ALLOWLIST, ASSET_DECIMALS, ASSET_PRICES, CONFIG, DCA_CURSOR, DCA_PLANS,
+    DCA_PLAN_COUNT, DCA_TOKEN_PLANS, DEPOSIT_TOKENS, KEEPERS, KEEPER_POOL, LAST_UPDATED,
+    PERMIT_QUERIES, PORTFOLIO, PORTFOLIO_ACCESS, PORTFOLIO_CONTRACTS, PORTFOLIO_LIMITS,
+    PORTFOLIO_LIST, QUEUED_SHARES, QUEUED_WITHDRAWS, QUEUED_WITHDRAW_COUNT, REGISTERED_ASSETS,
+    REWARDED_EPOCH, REWARD_EPOCH, ROUTE_CACHE, STRAY_TOKENS, UNUPDATED_LIST, UPDATE_BATCH,
+    USER_DCA_PLANS, USER_QUEUED_WITHDRAWS, VIEWING_KEY, WITHDRAW_CURSOR, WITHDRAW_QUEUES,
+    WITHDRAW_WINDOWS,
 };
 
+pub const BLOCK_SIZE: usize = 256;
+pub const NORMALIZATION_FACTOR: u32 = 18;
+// Levels of portfolio shares below a portfolio
+pub const MAX_NESTING_DEPTH: u32 = 3;
+pub const DEFAULT_UPDATE_BATCH: usize = 5;
+pub const DEFAULT_TOLERANCE_PERCENT: u128 = 5;
+pub const DEFAULT_MAX_PRICE_AGE: u64 = 3_600;
+pub const UPDATE_REPLY_ID: u64 = 1;
+pub const RELEASE_WITHDRAW_REPLY_ID: u64 = 2;
+pub const MAX_DCA_SCAN: u64 = 100;
+// Portfolios from the front of the unupdated list whose weights one Update reads
+pub const MAX_UPDATE_SCAN: usize = 20;
+pub const MAX_PAGE_SIZE: u32 = 100;
+pub const MAX_WITHDRAW_SCAN: u64 = 100;
+
 #[entry_point]
 pub fn instantiate(
     deps: DepsMut,
     _env: Env,
     info: MessageInfo,
     msg: InstantiateMsg,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let state = Config {
         admin: info.sender,
         swap_factory: msg.swap_factory,
@@ -25,6 +58,14 @@ pub fn instantiate(
         portfolio_code_id: msg.portfolio_code_id,
         accepted_deposit_tokens: msg.accepted_deposit_tokens.unwrap_or(vec![]),
         contract_status: ContractStatus::ACTIVE,
+        address_privacy: msg.address_privacy.unwrap_or_default(),
+        limit_bounds: msg.limit_bounds.unwrap_or_default(),
+        tolerance_percent: msg.tolerance_percent,
+        keeper_reward: msg.keeper_reward,
+        update_priority: msg.update_priority.unwrap_or_default(),
+        rebalance_interval: msg.rebalance_interval,
+        price_feeder: msg.price_feeder,
+        max_price_age: msg.max_price_age.unwrap_or(DEFAULT_MAX_PRICE_AGE),
     };
 
     CONFIG.save(deps.storage, &state)?;
@@ -32,12 +73,18 @@ pub fn instantiate(
     UNUPDATED_LIST.save(deps.storage, &vec![])?;
     REGISTERED_ASSETS.save(deps.storage, &vec![])?;
     VIEWING_KEY.save(deps.storage, &msg.viewing_key)?;
+    KEEPER_POOL.save(deps.storage, &Uint128::zero())?;
 
     Ok(Response::default())
 }
 
 #[entry_point]
-pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> StdResult<Response> {
+pub fn execute(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response, ContractError> {
     match msg {
         ExecuteMsg::UpdateConfig {
             admin,
@@ -47,6 +94,14 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> S
             snip20_code_id,
             accepted_deposit_tokens,
             contract_status,
+            address_privacy,
+            limit_bounds,
+            tolerance_percent,
+            keeper_reward,
+            update_priority,
+            rebalance_interval,
+            price_feeder,
+            max_price_age,
         } => try_update_config(
             deps,
             env,
@@ -58,9 +113,66 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> S
             snip20_code_id,
             accepted_deposit_tokens,
             contract_status,
+            address_privacy,
+            limit_bounds,
+            tolerance_percent,
+            keeper_reward,
+            update_priority,
+            rebalance_interval,
+            price_feeder,
+            max_price_age,
         ),
         ExecuteMsg::RegisterAssets { assets } => try_register_assets(deps, env, info, assets),
+        ExecuteMsg::SetPrices { prices } => try_set_prices(deps, env, info, prices),
+        ExecuteMsg::RegisterPortfolio {
+            portfolio,
+            deposit_tokens,
+        } => try_register_portfolio(deps, info, portfolio, deposit_tokens),
+        ExecuteMsg::SetRoute { key, route } => try_set_route(deps, info, key, route),
+        ExecuteMsg::SetPortfolioWeights {
+            portfolio_snip20,
+            config,
+        } => try_set_portfolio_weights(deps, info, portfolio_snip20, config),
+        ExecuteMsg::RegisterStrayToken { token } => try_register_stray_token(deps, info, token),
+        ExecuteMsg::RescueTokens {
+            token,
+            amount,
+            recipient,
+        } => try_rescue_tokens(deps, env, info, token, amount, recipient),
         ExecuteMsg::Update { batch_amount } => try_update(deps, env, info, batch_amount),
+        ExecuteMsg::DepositBasket {
+            portfolio_snip20,
+            min_shares,
+        } => try_deposit_basket(deps, info, portfolio_snip20, min_shares),
+        ExecuteMsg::CancelBasket { portfolio_snip20 } => {
+            try_cancel_basket(deps, info, portfolio_snip20)
+        }
+        ExecuteMsg::ExecuteDca { limit } => try_execute_dca(deps, env, limit),
+        ExecuteMsg::CancelDca { plan_id } => try_cancel_dca(deps, info, plan_id),
+        ExecuteMsg::SetViewingKey { key } => try_set_viewing_key(deps, info, key),
+        ExecuteMsg::ProcessWithdraws { limit } => try_process_withdraws(deps, env, limit),
+        ExecuteMsg::ReleaseWithdraw { request_id } => {
+            try_release_withdraw(deps, env, info, request_id)
+        }
+        ExecuteMsg::CancelWithdraw { request_id } => try_cancel_withdraw(deps, info, request_id),
+        ExecuteMsg::SetPortfolioAccess {
+            portfolio_snip20,
+            access,
+            permit_queries,
+        } => try_set_portfolio_access(deps, info, portfolio_snip20, access, permit_queries),
+        ExecuteMsg::UpdateAllowlist {
+            portfolio_snip20,
+            add,
+            remove,
+        } => try_update_allowlist(deps, info, portfolio_snip20, add, remove),
+        ExecuteMsg::SetPortfolioLimits {
+            portfolio_snip20,
+            limits,
+        } => try_set_portfolio_limits(deps, info, portfolio_snip20, limits),
+        ExecuteMsg::SetWithdrawQueue {
+            portfolio_snip20,
+            queue,
+        } => try_set_withdraw_queue(deps, info, portfolio_snip20, queue),
         ExecuteMsg::Receive {
             sender,
             from,
@@ -71,6 +183,7 @@ pub fn execute(deps: DepsMut, env: Env, info: MessageInfo, msg: ExecuteMsg) -> S
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn try_update_config(
     deps: DepsMut,
     env: Env,
@@ -82,12 +195,86 @@ pub fn try_update_config(
     snip20_code_id: Option<i32>,
     accepted_deposit_tokens: Option<Vec<ContractInfo>>,
     contract_status: Option<ContractStatus>,
-) -> StdResult<Response> {
-    let config = CONFIG.load(deps.storage)?;
+    address_privacy: Option<AddressPrivacy>,
+    limit_bounds: Option<LimitBounds>,
+    tolerance_percent: Option<u128>,
+    keeper_reward: Option<KeeperReward>,
+    update_priority: Option<UpdatePriority>,
+    rebalance_interval: Option<u64>,
+    price_feeder: Option<Addr>,
+    max_price_age: Option<u64>,
+) -> Result<Response, ContractError> {
+    let mut config = CONFIG.load(deps.storage)?;
     if info.sender != config.admin {
-        return Err(StdError::generic_err("Must be admin"));
+        return Err(ContractError::Unauthorized("admin".to_string()));
     }
-    Ok(Response::default())
+    if let Some(admin) = admin {
+        config.admin = admin;
+    }
+    if let Some(swap_factory) = swap_factory {
+        config.swap_factory = swap_factory;
+    }
+    if let Some(withdraw_fee) = withdraw_fee {
+        config.withdraw_fee =
+            Uint128::from(u128::try_from(withdraw_fee).map_err(|_| {
+                ContractError::InvalidConfig("fee must not be negative".to_string())
+            })?);
+    }
+    if let Some(create_fee) = create_fee {
+        config.create_fee =
+            Uint128::from(u128::try_from(create_fee).map_err(|_| {
+                ContractError::InvalidConfig("fee must not be negative".to_string())
+            })?);
+    }
+    if let Some(snip20_code_id) = snip20_code_id {
+        config.snip20_code_id = snip20_code_id;
+    }
+    if let Some(accepted_deposit_tokens) = accepted_deposit_tokens {
+        config.accepted_deposit_tokens = accepted_deposit_tokens;
+    }
+    if let Some(contract_status) = contract_status {
+        config.contract_status = contract_status;
+    }
+    if let Some(address_privacy) = address_privacy {
+        config.address_privacy = address_privacy;
+    }
+    // Limits already set stay until their creators change them
+    if let Some(limit_bounds) = limit_bounds {
+        config.limit_bounds = limit_bounds;
+    }
+    if let Some(tolerance_percent) = tolerance_percent {
+        config.tolerance_percent = Some(tolerance_percent);
+    }
+    if let Some(keeper_reward) = keeper_reward {
+        // The pool is held in the old token, it has to be paid out first
+        let pool = KEEPER_POOL.may_load(deps.storage)?.unwrap_or_default();
+        let same_token = matches!(&config.keeper_reward, Some(x) if x.token.address == keeper_reward.token.address);
+        if !pool.is_zero() && !same_token {
+            return Err(ContractError::InvalidConfig(
+                "keeper pool must be empty to change the reward token".to_string(),
+            ));
+        }
+        if keeper_reward.epoch_length == 0 {
+            return Err(ContractError::InvalidConfig(
+                "epoch length must not be zero".to_string(),
+            ));
+        }
+        config.keeper_reward = Some(keeper_reward);
+    }
+    if let Some(update_priority) = update_priority {
+        config.update_priority = update_priority;
+    }
+    if let Some(rebalance_interval) = rebalance_interval {
+        config.rebalance_interval = Some(rebalance_interval);
+    }
+    if let Some(price_feeder) = price_feeder {
+        config.price_feeder = Some(price_feeder);
+    }
+    if let Some(max_price_age) = max_price_age {
+        config.max_price_age = max_price_age;
+    }
+    CONFIG.save(deps.storage, &config)?;
+    Ok(Response::new().add_attribute_plaintext("action", "update_config"))
 }
 
 pub fn try_register_assets(
@@ -95,12 +282,238 @@ pub fn try_register_assets(
     env: Env,
     info: MessageInfo,
     assets: Vec<ContractInfo>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
     if info.sender != config.admin {
-        return Err(StdError::generic_err("Must be admin"));
+        return Err(ContractError::Unauthorized("admin".to_string()));
     }
-    Ok(Response::default())
+    let mut registered = REGISTERED_ASSETS.load(deps.storage)?;
+    let mut events = vec![];
+    for asset in assets {
+        if registered.contains(&asset.address) {
+            continue;
+        }
+        // Prices are per whole token, values need the decimals to be in one unit
+        let token_info = snip20::token_info_query(
+            deps.querier,
+            BLOCK_SIZE,
+            asset.code_hash.clone(),
+            asset.address.clone().into_string(),
+        )?;
+        ASSET_DECIMALS.insert(deps.storage, &asset.address, &token_info.decimals)?;
+        events.push(Event::new("register_asset").add_attribute_plaintext("asset", &asset.address));
+        registered.push(asset.address);
+    }
+    REGISTERED_ASSETS.save(deps.storage, &registered)?;
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "register_assets")
+        .add_events(events))
+}
+
+pub fn try_set_prices(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    prices: Vec<SetPrice>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.admin && Some(&info.sender) != config.price_feeder.as_ref() {
+        return Err(ContractError::Unauthorized(
+            "admin or price feeder".to_string(),
+        ));
+    }
+    let mut events = vec![];
+    for price in prices {
+        if !ASSET_DECIMALS.contains(deps.storage, &price.asset) {
+            return Err(ContractError::InvalidToken(
+                "asset must be registered".to_string(),
+            ));
+        }
+        if price.price.is_zero() {
+            return Err(ContractError::InvalidAmount(
+                "price must not be zero".to_string(),
+            ));
+        }
+        ASSET_PRICES.insert(
+            deps.storage,
+            &price.asset,
+            &AssetPrice {
+                price: price.price,
+                updated: env.block.time.seconds(),
+            },
+        )?;
+        events.push(
+            Event::new("set_price")
+                .add_attribute_plaintext("asset", &price.asset)
+                .add_attribute_plaintext("price", price.price.to_string()),
+        );
+    }
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "set_prices")
+        .add_events(events))
+}
+
+pub fn try_set_route(
+    deps: DepsMut,
+    info: MessageInfo,
+    key: RouteKey,
+    route: Option<Vec<SwapContract>>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.admin {
+        return Err(ContractError::Unauthorized("admin".to_string()));
+    }
+    // Routes in and out of portfolio shares go through the factory and are never cached
+    if PORTFOLIO.contains(deps.storage, &key.0) || PORTFOLIO.contains(deps.storage, &key.1) {
+        return Err(ContractError::InvalidConfig(
+            "portfolio share routes are not cached".to_string(),
+        ));
+    }
+    let event = Event::new("set_route")
+        .add_attribute_plaintext("from", &key.0)
+        .add_attribute_plaintext("to", &key.1);
+    let event = match route {
+        Some(route) => {
+            if route.is_empty() {
+                return Err(ContractError::InvalidConfig(
+                    "route must have at least one hop".to_string(),
+                ));
+            }
+            let event = event.add_attribute_plaintext("hops", route.len().to_string());
+            ROUTE_CACHE.insert(deps.storage, &key, &route)?;
+            event
+        }
+        None => {
+            ROUTE_CACHE.remove(deps.storage, &key)?;
+            event.add_attribute_plaintext("hops", "0")
+        }
+    };
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "set_route")
+        .add_event(event))
+}
+
+pub fn try_set_portfolio_weights(
+    deps: DepsMut,
+    info: MessageInfo,
+    portfolio_snip20: Addr,
+    config: Vec<PortfolioConfig>,
+) -> Result<Response, ContractError> {
+    let mut portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    if info.sender != portfolio.contract.address {
+        return Err(ContractError::Unauthorized(
+            "portfolio contract".to_string(),
+        ));
+    }
+    check_nesting(deps.storage, &config, &mut vec![portfolio_snip20.clone()])?;
+    portfolio.config = config;
+    PORTFOLIO.insert(deps.storage, &portfolio_snip20, &portfolio)?;
+    Ok(Response::new().add_attribute_plaintext("action", "set_portfolio_weights"))
+}
+
+pub fn try_register_stray_token(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: ContractInfo,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.admin {
+        return Err(ContractError::Unauthorized("admin".to_string()));
+    }
+    check_stray_token(deps.storage, &config, &token.address)?;
+    STRAY_TOKENS.insert(deps.storage, &token.address, &token)?;
+    let msg = snip20::set_viewing_key_msg(
+        VIEWING_KEY.load(deps.storage)?,
+        None,
+        BLOCK_SIZE,
+        token.code_hash,
+        token.address.clone().into_string(),
+    )?;
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "register_stray_token")
+        .add_attribute_plaintext("token", &token.address)
+        .add_message(msg))
+}
+
+pub fn try_rescue_tokens(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    token: Addr,
+    amount: Option<Uint128>,
+    recipient: Addr,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if info.sender != config.admin {
+        return Err(ContractError::Unauthorized("admin".to_string()));
+    }
+    // Checked again, the token may have become an accepted deposit token since
+    check_stray_token(deps.storage, &config, &token)?;
+    let token = STRAY_TOKENS
+        .get(deps.storage, &token)
+        .ok_or_else(|| ContractError::NotFound("stray token".to_string()))?;
+    let amount = match amount {
+        Some(amount) => amount,
+        None => {
+            snip20::balance_query(
+                deps.querier,
+                env.contract.address.into_string(),
+                VIEWING_KEY.load(deps.storage)?,
+                BLOCK_SIZE,
+                token.code_hash.clone(),
+                token.address.clone().into_string(),
+            )?
+            .amount
+        }
+    };
+    if amount.is_zero() {
+        return Err(ContractError::InvalidAmount(
+            "nothing to rescue".to_string(),
+        ));
+    }
+    let msg = snip20::transfer_msg(
+        recipient.clone().into_string(),
+        amount,
+        None,
+        None,
+        BLOCK_SIZE,
+        token.code_hash,
+        token.address.clone().into_string(),
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "rescue_tokens")
+        .add_attribute_plaintext("token", &token.address)
+        .add_attribute_plaintext("amount", amount.to_string());
+    Ok(
+        add_address_attribute(response, "recipient", &recipient, &config.address_privacy)
+            .add_message(msg),
+    )
+}
+
+// Tokens the factory holds for users, deposit tokens in DCA plans and shares in the withdraw
+// queue, can never be rescued
+fn check_stray_token(
+    storage: &dyn Storage,
+    config: &Config,
+    token: &Addr,
+) -> Result<(), ContractError> {
+    let held = config
+        .accepted_deposit_tokens
+        .iter()
+        .any(|x| &x.address == token)
+        || PORTFOLIO.contains(storage, token)
+        || REGISTERED_ASSETS
+            .may_load(storage)?
+            .unwrap_or_default()
+            .contains(token);
+    if held || matches!(&config.keeper_reward, Some(x) if &x.token.address == token) {
+        return Err(ContractError::RescueForbidden);
+    }
+    if DCA_TOKEN_PLANS.get(storage, token).unwrap_or(0) > 0 {
+        return Err(ContractError::RescueForbidden);
+    }
+    Ok(())
 }
 
 pub fn try_update(
@@ -108,12 +521,333 @@ pub fn try_update(
     env: Env,
     info: MessageInfo,
     batch_amount: Option<Uint128>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     let config = CONFIG.load(deps.storage)?;
-    if info.sender != config.admin {
-        return Err(StdError::generic_err("Must be admin"));
+    match config.contract_status {
+        ContractStatus::ACTIVE => {}
+        ContractStatus::PROTECTED => return Err(ContractError::Protected),
+        ContractStatus::FROZEN => return Err(ContractError::Frozen),
     }
-    Ok(Response::default())
+    let mut unupdated = UNUPDATED_LIST.load(deps.storage)?;
+    if unupdated.is_empty() {
+        unupdated = PORTFOLIO_LIST.load(deps.storage)?;
+    }
+    if unupdated.is_empty() {
+        return Err(ContractError::NotFound("portfolio to update".to_string()));
+    }
+    let tolerance_percent = config
+        .tolerance_percent
+        .unwrap_or(DEFAULT_TOLERANCE_PERCENT);
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+
+    // Only a window of the list is read, the rest waits for the next Update
+    // Portfolios that don't need an update, or can't be read, are dropped until the list is reset
+    let scanned: Vec<Addr> = unupdated
+        .drain(..unupdated.len().min(MAX_UPDATE_SCAN))
+        .collect();
+    let mut candidates = vec![];
+    let mut events = vec![];
+    for portfolio_snip20 in scanned {
+        match update_candidate(
+            deps.as_ref(),
+            &env,
+            &config,
+            &portfolio_snip20,
+            tolerance_percent,
+            &viewing_key,
+        ) {
+            Ok(Some(candidate)) => candidates.push(candidate),
+            Ok(None) => {}
+            Err(err) => events.push(add_event_address(
+                Event::new("update_skipped").add_attribute_plaintext("error", err.to_string()),
+                "portfolio",
+                &portfolio_snip20,
+                &config.address_privacy,
+            )),
+        }
+    }
+    match config.update_priority {
+        UpdatePriority::Drift => candidates.sort_by(|a, b| b.drift.cmp(&a.drift)),
+        UpdatePriority::Staleness => candidates.sort_by_key(|x| x.last_updated),
+    }
+    let batch_amount = batch_amount
+        .map(|x| usize::try_from(x.u128()).unwrap_or(usize::MAX))
+        .unwrap_or(DEFAULT_UPDATE_BATCH)
+        .min(candidates.len());
+    let batch: Vec<Addr> = candidates
+        .drain(..batch_amount)
+        .map(|x| x.portfolio_snip20)
+        .collect();
+    let mut remaining: Vec<Addr> = candidates.into_iter().map(|x| x.portfolio_snip20).collect();
+    remaining.append(&mut unupdated);
+    UNUPDATED_LIST.save(deps.storage, &remaining)?;
+
+    let mut submessages = vec![];
+    for portfolio_snip20 in &batch {
+        let portfolio = load_portfolio(deps.storage, portfolio_snip20)?;
+        // A failed portfolio is skipped, it is retried once the list is reset
+        submessages.push(SubMsg::reply_always(
+            portfolio_execute_msg(
+                portfolio.contract,
+                &PortfolioExecuteMsg::Update { tolerance_percent },
+            )?,
+            UPDATE_REPLY_ID,
+        ));
+    }
+    if !batch.is_empty() {
+        UPDATE_BATCH.save(
+            deps.storage,
+            &UpdateBatch {
+                keeper: info.sender.clone(),
+                portfolios: batch,
+            },
+        )?;
+    }
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "update")
+        .add_attribute_plaintext("portfolios", submessages.len().to_string());
+    Ok(
+        add_address_attribute(response, "keeper", &info.sender, &config.address_privacy)
+            .add_events(events)
+            .add_submessages(submessages),
+    )
+}
+
+// None when the portfolio is within tolerance and its rebalance interval hasn't passed
+// A portfolio whose weights can't be read would fail to update as well
+fn update_candidate(
+    deps: Deps,
+    env: &Env,
+    config: &Config,
+    portfolio_snip20: &Addr,
+    tolerance_percent: u128,
+    viewing_key: &str,
+) -> Result<Option<RebalanceCandidate>, ContractError> {
+    let portfolio = load_portfolio(deps.storage, portfolio_snip20)?;
+    let (weights, rebalance_due, deposit_swaps) = query_portfolio_weights(
+        &deps.querier,
+        portfolio.contract,
+        Some(tolerance_percent),
+        Some(viewing_key.to_string()),
+    )?;
+    let last_updated = LAST_UPDATED
+        .get(deps.storage, portfolio_snip20)
+        .unwrap_or(0);
+    let interval_passed = matches!(
+        config.rebalance_interval,
+        Some(interval) if env.block.time.seconds() >= last_updated.saturating_add(interval)
+    );
+    if !rebalance_due && !interval_passed {
+        return Ok(None);
+    }
+    Ok(Some(RebalanceCandidate {
+        portfolio_snip20: portfolio_snip20.clone(),
+        drift: weights.iter().map(|x| x.drift).max().unwrap_or_default(),
+        last_updated,
+        interval_passed,
+        estimated_swaps: estimate_swaps(&weights, deposit_swaps),
+    }))
+}
+
+// Update sells each over target asset into the under target ones in order until its excess
+// is used up, one swap per pair it reaches
+fn estimate_swaps(weights: &[WeightItem], deposit_swaps: u32) -> u32 {
+    let mut under_target: Vec<Decimal256> = weights
+        .iter()
+        .filter(|x| x.rebalance_due && !x.over_target)
+        .map(|x| x.drift)
+        .collect();
+    let mut swaps = deposit_swaps;
+    for over_target in weights.iter().filter(|x| x.rebalance_due && x.over_target) {
+        let mut excess = over_target.drift;
+        for shortfall in under_target.iter_mut() {
+            if excess.is_zero() {
+                break;
+            }
+            if shortfall.is_zero() {
+                continue;
+            }
+            let matched = excess.min(*shortfall);
+            excess -= matched;
+            *shortfall -= matched;
+            swaps += 1;
+        }
+    }
+    swaps
+}
+
+#[entry_point]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    match msg.id {
+        UPDATE_REPLY_ID => {
+            let events = match msg.result {
+                SubMsgResult::Ok(response) => Some(response.events),
+                SubMsgResult::Err(_) => None,
+            };
+            try_finish_update(deps, env, events)
+        }
+        // Only sent on error, the request was left queued
+        RELEASE_WITHDRAW_REPLY_ID => {
+            let error = match msg.result {
+                SubMsgResult::Err(error) => error,
+                SubMsgResult::Ok(_) => String::new(),
+            };
+            Ok(Response::new().add_event(
+                Event::new("release_withdraw_failed").add_attribute_plaintext("error", error),
+            ))
+        }
+        _ => Err(ContractError::UnknownReply(msg.id)),
+    }
+}
+
+// Swaps the portfolio reported for its Update, a no-op Update reports none
+// Events from nested portfolios the Update deposited into or withdrew from are not counted
+fn count_swaps(events: &[Event], contract: &Addr) -> u64 {
+    events
+        .iter()
+        .filter(|x| x.ty == "wasm")
+        .filter(|x| {
+            x.attributes
+                .iter()
+                .any(|x| x.key == "contract_address" && x.value == contract.as_str())
+        })
+        .flat_map(|x| x.attributes.iter())
+        .filter(|x| x.key == "rebalance_swaps")
+        .filter_map(|x| x.value.parse::<u64>().ok())
+        .sum()
+}
+
+// Replies arrive in the order the batch was sent, events is None when the Update failed
+fn try_finish_update(
+    deps: DepsMut,
+    env: Env,
+    events: Option<Vec<Event>>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let mut batch = UPDATE_BATCH.load(deps.storage)?;
+    if batch.portfolios.is_empty() {
+        return Err(ContractError::NotFound("portfolio update".to_string()));
+    }
+    let portfolio_snip20 = batch.portfolios.remove(0);
+    if batch.portfolios.is_empty() {
+        UPDATE_BATCH.remove(deps.storage);
+    } else {
+        UPDATE_BATCH.save(deps.storage, &batch)?;
+    }
+
+    let mut stats = KEEPERS.get(deps.storage, &batch.keeper).unwrap_or_default();
+    stats.last_active = env.block.time.seconds();
+    let event = add_event_address(
+        Event::new("keeper_update"),
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    );
+    let swaps = match (events, PORTFOLIO.get(deps.storage, &portfolio_snip20)) {
+        (Some(events), Some(portfolio)) => Some(count_swaps(&events, &portfolio.contract.address)),
+        _ => None,
+    };
+    let mut messages = vec![];
+    let event = match swaps {
+        // Nothing was traded, the keeper is not paid for it
+        Some(0) => {
+            LAST_UPDATED.insert(deps.storage, &portfolio_snip20, &stats.last_active)?;
+            event.add_attribute_plaintext("status", "unchanged")
+        }
+        Some(swaps) => {
+            LAST_UPDATED.insert(deps.storage, &portfolio_snip20, &stats.last_active)?;
+            stats.rebalances += 1;
+            let mut reward = Uint128::zero();
+            if let Some(keeper_reward) = &config.keeper_reward {
+                reward = pay_keeper_reward(deps.storage, &env, keeper_reward, &portfolio_snip20)?;
+                if !reward.is_zero() {
+                    stats.rewards = stats.rewards.checked_add(reward)?;
+                    messages.push(snip20::transfer_msg(
+                        batch.keeper.clone().into_string(),
+                        reward,
+                        None,
+                        None,
+                        BLOCK_SIZE,
+                        keeper_reward.token.code_hash.clone(),
+                        keeper_reward.token.address.clone().into_string(),
+                    )?);
+                }
+            }
+            event
+                .add_attribute_plaintext("status", "rebalanced")
+                .add_attribute_plaintext("swaps", swaps.to_string())
+                .add_attribute_plaintext("reward", reward.to_string())
+        }
+        None => {
+            stats.failed += 1;
+            event.add_attribute_plaintext("status", "failed")
+        }
+    };
+    KEEPERS.insert(deps.storage, &batch.keeper, &stats)?;
+    Ok(Response::new().add_event(event).add_messages(messages))
+}
+
+// Each portfolio earns one reward per epoch, limited by the epoch cap and what is in the pool
+fn pay_keeper_reward(
+    storage: &mut dyn Storage,
+    env: &Env,
+    keeper_reward: &KeeperReward,
+    portfolio_snip20: &Addr,
+) -> Result<Uint128, ContractError> {
+    let epoch = env.block.time.seconds() / keeper_reward.epoch_length.max(1);
+    if REWARDED_EPOCH.get(storage, portfolio_snip20) == Some(epoch) {
+        return Ok(Uint128::zero());
+    }
+    let mut paid = REWARD_EPOCH
+        .may_load(storage)?
+        .filter(|x| x.epoch == epoch)
+        .unwrap_or(RewardEpoch {
+            epoch,
+            paid: Uint128::zero(),
+        });
+    let pool = KEEPER_POOL.may_load(storage)?.unwrap_or_default();
+    let amount = keeper_reward
+        .per_rebalance
+        .min(keeper_reward.epoch_cap.saturating_sub(paid.paid))
+        .min(pool);
+    if amount.is_zero() {
+        return Ok(amount);
+    }
+    paid.paid += amount;
+    REWARD_EPOCH.save(storage, &paid)?;
+    KEEPER_POOL.save(storage, &(pool - amount))?;
+    REWARDED_EPOCH.insert(storage, portfolio_snip20, &epoch)?;
+    Ok(amount)
+}
+
+pub fn try_fund_keeper_rewards(
+    deps: DepsMut,
+    info: MessageInfo,
+    from: Addr,
+    amount: Uint256,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    // The admin funds the pool out of the withdraw fees it is paid
+    if from != config.admin {
+        return Err(ContractError::Unauthorized("admin".to_string()));
+    }
+    match &config.keeper_reward {
+        Some(keeper_reward) if keeper_reward.token.address == info.sender => {}
+        _ => {
+            return Err(ContractError::InvalidToken(
+                "must be the keeper reward token".to_string(),
+            ))
+        }
+    }
+    let pool = KEEPER_POOL
+        .may_load(deps.storage)?
+        .unwrap_or_default()
+        .checked_add(Uint128::try_from(amount)?)?;
+    KEEPER_POOL.save(deps.storage, &pool)?;
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "fund_keeper_rewards")
+        .add_attribute_plaintext("pool", pool.to_string()))
 }
 
 pub fn try_receive(
@@ -124,47 +858,167 @@ pub fn try_receive(
     from: Addr,
     amount: Uint256,
     msg: Option<Binary>,
-) -> StdResult<Response> {
+) -> Result<Response, ContractError> {
     if let Some(x) = msg {
         match from_binary(&x)? {
-            ReceiveMsg::CreatePortfolio { config, name } => {
-                try_create_portfolio(deps, env, info, config, name)
-            }
-            ReceiveMsg::Deposit { portfolio_snip20 } => {
-                try_deposit(deps, env, info, sender, from, portfolio_snip20)
+            // Contracts can't be instantiated from here yet, the admin registers portfolios
+            ReceiveMsg::CreatePortfolio { .. } => Err(ContractError::InvalidConfig(
+                "portfolio creation is not supported, portfolios are registered by the admin"
+                    .to_string(),
+            )),
+            ReceiveMsg::Deposit {
+                portfolio_snip20,
+                gate_key,
+            } => try_deposit(
+                deps,
+                env,
+                info,
+                sender,
+                from,
+                amount,
+                portfolio_snip20,
+                gate_key,
+            ),
+            ReceiveMsg::Withdraw { zap_out } => {
+                try_withdraw(deps, env, info, sender, from, amount, zap_out)
             }
-            ReceiveMsg::Withdraw {} => try_withdraw(deps, env, info, sender, from),
-            _ => Err(StdError::generic_err("Snip20 msg not recognized")),
+            ReceiveMsg::StageBasket {
+                portfolio_snip20,
+                gate_key,
+            } => try_stage_basket(deps, info, from, amount, portfolio_snip20, gate_key),
+            ReceiveMsg::CreateDcaPlan {
+                portfolio_snip20,
+                tranches,
+                interval,
+                gate_key,
+            } => try_create_dca_plan(
+                deps,
+                env,
+                info,
+                from,
+                amount,
+                portfolio_snip20,
+                tranches,
+                interval,
+                gate_key,
+            ),
+            ReceiveMsg::FundKeeperRewards {} => try_fund_keeper_rewards(deps, info, from, amount),
+            _ => Err(ContractError::UnknownMessage),
         }
     } else {
         Ok(Response::default())
     }
 }
 
-pub fn try_create_portfolio(
+pub fn try_register_portfolio(
     deps: DepsMut,
-    env: Env,
     info: MessageInfo,
-    config: Vec<PortfolioConfig>,
-    name: String,
-) -> StdResult<Response> {
-    // check if is valid config
-    // instantiate portfolio
-    // instantiate snip20
-    Ok(Response::default())
+    portfolio: Portfolio,
+    deposit_tokens: Option<Vec<Addr>>,
+) -> Result<Response, ContractError> {
+    let factory_config = CONFIG.load(deps.storage)?;
+    if info.sender != factory_config.admin {
+        return Err(ContractError::Unauthorized("admin".to_string()));
+    }
+    if PORTFOLIO.contains(deps.storage, &portfolio.snip20.address) {
+        return Err(ContractError::InvalidConfig(
+            "portfolio is already registered".to_string(),
+        ));
+    }
+    check_nesting(
+        deps.storage,
+        &portfolio.config,
+        &mut vec![portfolio.snip20.address.clone()],
+    )?;
+    let deposit_tokens = match deposit_tokens {
+        Some(deposit_tokens) => deposit_tokens
+            .iter()
+            .map(|token| {
+                factory_config
+                    .accepted_deposit_tokens
+                    .iter()
+                    .find(|x| &x.address == token)
+                    .cloned()
+                    .ok_or_else(|| {
+                        ContractError::InvalidToken("must be a valid deposit token".to_string())
+                    })
+            })
+            .collect::<Result<Vec<ContractInfo>, ContractError>>()?,
+        None => factory_config.accepted_deposit_tokens,
+    };
+
+    PORTFOLIO.insert(deps.storage, &portfolio.snip20.address, &portfolio)?;
+    PORTFOLIO_CONTRACTS.insert(
+        deps.storage,
+        &portfolio.contract.address,
+        &portfolio.snip20.address,
+    )?;
+    DEPOSIT_TOKENS.insert(deps.storage, &portfolio.snip20.address, &deposit_tokens)?;
+    let mut portfolio_list = PORTFOLIO_LIST.load(deps.storage)?;
+    portfolio_list.push(portfolio.snip20.address.clone());
+    PORTFOLIO_LIST.save(deps.storage, &portfolio_list)?;
+
+    let event = portfolio.config.iter().fold(
+        Event::new("register_portfolio").add_attribute_plaintext("name", &portfolio.name),
+        |event, position| {
+            event
+                .add_attribute_plaintext("asset", &position.asset.address)
+                .add_attribute_plaintext("percent", position.percent.to_string())
+        },
+    );
+    let event = deposit_tokens.iter().fold(event, |event, token| {
+        event.add_attribute_plaintext("deposit_token", &token.address)
+    });
+    let event = add_event_address(
+        event,
+        "portfolio",
+        &portfolio.snip20.address,
+        &factory_config.address_privacy,
+    );
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "register_portfolio")
+        .add_event(event))
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn try_deposit(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
     sender: Addr,
     from: Addr,
+    amount: Uint256,
     portfolio_snip20: Addr,
-) -> StdResult<Response> {
-    // Check if is valid deposit asset
+    gate_key: Option<String>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.contract_status != ContractStatus::ACTIVE {
+        return Err(ContractError::DepositsDisabled);
+    }
     // Check if is valid Portfolio snip20
-    Ok(Response::default())
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    check_access(deps.storage, &deps.querier, &portfolio, &from, gate_key)?;
+    // Check if is valid deposit asset for this portfolio
+    let deposit_token = load_deposit_token(deps.storage, &portfolio_snip20, &info.sender)?;
+    let msg = portfolio_deposit_msg(
+        &portfolio,
+        &deposit_token,
+        Uint128::try_from(amount)?,
+        from.clone(),
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "deposit")
+        .add_attribute_plaintext("asset", &info.sender)
+        .add_attribute_plaintext("amount", amount.to_string());
+    let response = add_address_attribute(
+        response,
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    );
+    let response = add_address_attribute(response, "depositor", &from, &config.address_privacy);
+    Ok(response.add_message(msg))
 }
 
 pub fn try_withdraw(
@@ -173,25 +1027,1917 @@ pub fn try_withdraw(
     info: MessageInfo,
     sender: Addr,
     from: Addr,
-) -> StdResult<Response> {
+    amount: Uint256,
+    zap_out: Option<ZapOut>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.contract_status == ContractStatus::FROZEN {
+        return Err(ContractError::Frozen);
+    }
     // check if is valid portfolio snip20 (sender)
+    let portfolio = load_portfolio(deps.storage, &info.sender)?;
+    let amount = Uint128::try_from(amount)?;
+    let limits = PORTFOLIO_LIMITS
+        .get(deps.storage, &info.sender)
+        .unwrap_or_default();
+    // Emergency exits take any amount
+    if config.contract_status != ContractStatus::PROTECTED
+        && matches!(limits.min_withdraw, Some(min) if amount < min)
+    {
+        return Err(ContractError::LimitExceeded(
+            "withdraw is below the portfolio minimum".to_string(),
+        ));
+    }
+    // Emergency withdrawals skip the queue
+    if let Some(queue) = WITHDRAW_QUEUES.get(deps.storage, &info.sender) {
+        if config.contract_status != ContractStatus::PROTECTED {
+            // The threshold covers everything the owner withdrew in the window, not one request
+            let window = env.block.time.seconds() / queue_length(&queue.release);
+            let windows = WITHDRAW_WINDOWS.add_suffix(info.sender.as_bytes());
+            let withdrawn = match windows.get(deps.storage, &from) {
+                Some(x) if x.window == window => x.amount,
+                _ => Uint128::zero(),
+            };
+            if withdrawn.saturating_add(amount) > queue.threshold {
+                check_zap_out(deps.storage, &portfolio, &zap_out)?;
+                return queue_withdraw(
+                    deps,
+                    env,
+                    &config,
+                    &queue,
+                    info.sender,
+                    from,
+                    amount,
+                    zap_out,
+                );
+            }
+            windows.insert(
+                deps.storage,
+                &from,
+                &WithdrawWindow {
+                    window,
+                    amount: withdrawn.saturating_add(amount),
+                },
+            )?;
+        }
+    }
+    let total_supply = share_supply(&deps.querier, &portfolio)?;
+    let messages = withdraw_msgs(
+        &config,
+        &portfolio,
+        amount,
+        total_supply,
+        from.clone(),
+        zap_out,
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "withdraw")
+        .add_attribute_plaintext("amount", amount.to_string());
+    let response =
+        add_address_attribute(response, "portfolio", &info.sender, &config.address_privacy);
+    let response = add_address_attribute(response, "receiver", &from, &config.address_privacy);
+    Ok(response.add_messages(messages))
+}
+
+fn queue_length(release: &QueueRelease) -> u64 {
+    match release {
+        QueueRelease::Cooldown { seconds } => (*seconds).max(1),
+        QueueRelease::Epoch { length } => (*length).max(1),
+    }
+}
+
+// Checked before shares are escrowed so a request can't sit in the queue until it fails
+fn check_zap_out(
+    storage: &dyn Storage,
+    portfolio: &Portfolio,
+    zap_out: &Option<ZapOut>,
+) -> Result<(), ContractError> {
+    let zap_out = match zap_out {
+        Some(zap_out) => zap_out,
+        None => return Ok(()),
+    };
+    let held = portfolio
+        .config
+        .iter()
+        .any(|x| x.asset.address == zap_out.asset.address)
+        || DEPOSIT_TOKENS
+            .get(storage, &portfolio.snip20.address)
+            .unwrap_or_default()
+            .iter()
+            .any(|x| x.address == zap_out.asset.address);
+    if !held {
+        return Err(ContractError::InvalidToken(
+            "zap out asset must be a portfolio asset".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Holds the shares here until the portfolio's queue releases them
+#[allow(clippy::too_many_arguments)]
+fn queue_withdraw(
+    deps: DepsMut,
+    env: Env,
+    config: &Config,
+    queue: &WithdrawQueueConfig,
+    portfolio_snip20: Addr,
+    owner: Addr,
+    amount: Uint128,
+    zap_out: Option<ZapOut>,
+) -> Result<Response, ContractError> {
+    let now = env.block.time.seconds();
+    let ready_at = match queue.release {
+        QueueRelease::Cooldown { seconds } => now.saturating_add(seconds),
+        QueueRelease::Epoch { length } => (now / length).saturating_add(1).saturating_mul(length),
+    };
+    let id = QUEUED_WITHDRAW_COUNT.may_load(deps.storage)?.unwrap_or(0);
+    QUEUED_WITHDRAW_COUNT.save(deps.storage, &(id + 1))?;
+    let request = QueuedWithdraw {
+        id,
+        owner: owner.clone(),
+        portfolio_snip20: portfolio_snip20.clone(),
+        amount,
+        zap_out,
+        ready_at,
+    };
+    QUEUED_WITHDRAWS.insert(deps.storage, &id, &request)?;
+    USER_QUEUED_WITHDRAWS
+        .add_suffix(owner.as_bytes())
+        .insert(deps.storage, &id, &true)?;
+    let queued = QUEUED_SHARES
+        .get(deps.storage, &portfolio_snip20)
+        .unwrap_or(Uint128::zero());
+    QUEUED_SHARES.insert(
+        deps.storage,
+        &portfolio_snip20,
+        &queued.saturating_add(amount),
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "queue_withdraw")
+        .add_attribute_plaintext("request_id", id.to_string())
+        .add_attribute_plaintext("amount", amount.to_string())
+        .add_attribute_plaintext("ready_at", ready_at.to_string());
+    let response = add_address_attribute(
+        response,
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    );
+    Ok(add_address_attribute(
+        response,
+        "receiver",
+        &owner,
+        &config.address_privacy,
+    ))
+}
+
+// Burns amount of the portfolio's shares held here and pays their slice out to receiver
+// While PROTECTED the portfolio pays raw balances and zap outs are dropped
+fn withdraw_msgs(
+    config: &Config,
+    portfolio: &Portfolio,
+    amount: Uint128,
+    total_supply: Uint128,
+    receiver: Addr,
+    zap_out: Option<ZapOut>,
+) -> Result<Vec<CosmosMsg>, ContractError> {
+    if total_supply.is_zero() {
+        return Err(ContractError::InvalidAmount(
+            "portfolio has no shares".to_string(),
+        ));
+    }
+    let share = amount.multiply_ratio(Uint128::new(10).pow(NORMALIZATION_FACTOR), total_supply);
     // withdraw from portfolio contract
+    let withdraw_msg = match config.contract_status {
+        ContractStatus::PROTECTED => PortfolioExecuteMsg::EmergencyWithdraw { share, receiver },
+        _ => PortfolioExecuteMsg::Withdraw {
+            share,
+            receiver,
+            fee: config.withdraw_fee.u128(),
+            zap_out,
+        },
+    };
+    let withdraw = portfolio_execute_msg(portfolio.contract.clone(), &withdraw_msg)?;
     // burn snip20
-    Ok(Response::default())
+    let burn = snip20::burn_msg(
+        amount,
+        None,
+        None,
+        BLOCK_SIZE,
+        portfolio.snip20.code_hash.clone(),
+        portfolio.snip20.address.clone().into_string(),
+    )?;
+    Ok(vec![withdraw, burn])
+}
+
+fn share_supply(querier: &QuerierWrapper, portfolio: &Portfolio) -> Result<Uint128, ContractError> {
+    let token_info = snip20::token_info_query(
+        *querier,
+        BLOCK_SIZE,
+        portfolio.snip20.code_hash.clone(),
+        portfolio.snip20.address.clone().into_string(),
+    )?;
+    Ok(token_info.total_supply.unwrap_or(Uint128::zero()))
+}
+
+pub fn try_process_withdraws(
+    deps: DepsMut,
+    env: Env,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.contract_status == ContractStatus::FROZEN {
+        return Err(ContractError::Frozen);
+    }
+    let now = env.block.time.seconds();
+    let limit = limit.unwrap_or(u32::MAX) as usize;
+    // Request ids are sequential, check at most MAX_WITHDRAW_SCAN from where the last call stopped
+    let request_count = QUEUED_WITHDRAW_COUNT.may_load(deps.storage)?.unwrap_or(0);
+    let cursor = WITHDRAW_CURSOR.may_load(deps.storage)?.unwrap_or(0);
+    let mut released = vec![];
+    let mut scanned = 0;
+    while scanned < request_count.min(MAX_WITHDRAW_SCAN) && released.len() < limit {
+        let id = (cursor + scanned) % request_count;
+        scanned += 1;
+        if let Some(request) = QUEUED_WITHDRAWS.get(deps.storage, &id) {
+            if request.ready_at <= now {
+                released.push(request.id);
+            }
+        }
+    }
+    if request_count > 0 {
+        WITHDRAW_CURSOR.save(deps.storage, &((cursor + scanned) % request_count))?;
+    }
+
+    // Each payout runs on its own so a failing one is reverted and stays queued
+    let mut submessages = vec![];
+    for request_id in &released {
+        submessages.push(SubMsg::reply_on_error(
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: env.contract.address.to_string(),
+                code_hash: env.contract.code_hash.clone(),
+                msg: to_binary(&ExecuteMsg::ReleaseWithdraw {
+                    request_id: *request_id,
+                })?,
+                funds: vec![],
+            }),
+            RELEASE_WITHDRAW_REPLY_ID,
+        ));
+    }
+
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "process_withdraws")
+        .add_attribute_plaintext("withdraws", released.len().to_string())
+        .add_submessages(submessages))
+}
+
+// Supply is read when the payout runs, after the earlier payouts in the batch have burned
+pub fn try_release_withdraw(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    request_id: u64,
+) -> Result<Response, ContractError> {
+    if info.sender != env.contract.address {
+        return Err(ContractError::Unauthorized("factory contract".to_string()));
+    }
+    let config = CONFIG.load(deps.storage)?;
+    let request = QUEUED_WITHDRAWS
+        .get(deps.storage, &request_id)
+        .ok_or_else(|| ContractError::NotFound("withdraw request".to_string()))?;
+    let portfolio = load_portfolio(deps.storage, &request.portfolio_snip20)?;
+    let total_supply = share_supply(&deps.querier, &portfolio)?;
+    let messages = withdraw_msgs(
+        &config,
+        &portfolio,
+        request.amount,
+        total_supply,
+        request.owner.clone(),
+        request.zap_out.clone(),
+    )?;
+    remove_queued_withdraw(deps.storage, &request)?;
+    let event = add_event_address(
+        Event::new("queued_withdraw")
+            .add_attribute_plaintext("request_id", request.id.to_string())
+            .add_attribute_plaintext("amount", request.amount.to_string()),
+        "portfolio",
+        &request.portfolio_snip20,
+        &config.address_privacy,
+    );
+
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "release_withdraw")
+        .add_event(event)
+        .add_messages(messages))
+}
+
+pub fn try_cancel_withdraw(
+    deps: DepsMut,
+    info: MessageInfo,
+    request_id: u64,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let request = QUEUED_WITHDRAWS
+        .get(deps.storage, &request_id)
+        .ok_or_else(|| ContractError::NotFound("withdraw request".to_string()))?;
+    if request.owner != info.sender {
+        return Err(ContractError::Unauthorized("request owner".to_string()));
+    }
+    let portfolio = load_portfolio(deps.storage, &request.portfolio_snip20)?;
+    remove_queued_withdraw(deps.storage, &request)?;
+    let msg = snip20::transfer_msg(
+        request.owner.clone().into_string(),
+        request.amount,
+        None,
+        None,
+        BLOCK_SIZE,
+        portfolio.snip20.code_hash,
+        portfolio.snip20.address.into_string(),
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "cancel_withdraw")
+        .add_attribute_plaintext("request_id", request_id.to_string())
+        .add_attribute_plaintext("amount", request.amount.to_string());
+    Ok(add_address_attribute(
+        response,
+        "receiver",
+        &request.owner,
+        &config.address_privacy,
+    )
+    .add_message(msg))
+}
+
+fn remove_queued_withdraw(storage: &mut dyn Storage, request: &QueuedWithdraw) -> StdResult<()> {
+    QUEUED_WITHDRAWS.remove(storage, &request.id)?;
+    USER_QUEUED_WITHDRAWS
+        .add_suffix(request.owner.as_bytes())
+        .remove(storage, &request.id)?;
+    let queued = QUEUED_SHARES
+        .get(storage, &request.portfolio_snip20)
+        .unwrap_or(Uint128::zero());
+    QUEUED_SHARES.insert(
+        storage,
+        &request.portfolio_snip20,
+        &queued.saturating_sub(request.amount),
+    )
+}
+
+pub fn try_stage_basket(
+    deps: DepsMut,
+    info: MessageInfo,
+    from: Addr,
+    amount: Uint256,
+    portfolio_snip20: Addr,
+    gate_key: Option<String>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.contract_status != ContractStatus::ACTIVE {
+        return Err(ContractError::DepositsDisabled);
+    }
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    check_access(deps.storage, &deps.querier, &portfolio, &from, gate_key)?;
+    let asset = portfolio
+        .config
+        .iter()
+        .find(|x| x.asset.address == info.sender)
+        .ok_or_else(|| ContractError::InvalidToken("must be a portfolio asset".to_string()))?;
+    let msg = snip20::send_msg_with_code_hash(
+        portfolio.contract.address.into_string(),
+        Some(portfolio.contract.code_hash),
+        Uint128::try_from(amount)?,
+        Some(to_binary(&PortfolioReceiveMsg::StageBasket {
+            depositor: from.clone(),
+        })?),
+        None,
+        None,
+        BLOCK_SIZE,
+        asset.asset.code_hash.clone(),
+        asset.asset.address.clone().into_string(),
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "stage_basket")
+        .add_attribute_plaintext("asset", &info.sender)
+        .add_attribute_plaintext("amount", amount.to_string());
+    let response = add_address_attribute(
+        response,
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    );
+    let response = add_address_attribute(response, "depositor", &from, &config.address_privacy);
+    Ok(response.add_message(msg))
+}
+
+pub fn try_deposit_basket(
+    deps: DepsMut,
+    info: MessageInfo,
+    portfolio_snip20: Addr,
+    min_shares: Option<Uint128>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.contract_status != ContractStatus::ACTIVE {
+        return Err(ContractError::DepositsDisabled);
+    }
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    let msg = portfolio_execute_msg(
+        portfolio.contract,
+        &PortfolioExecuteMsg::DepositBasket {
+            depositor: info.sender.clone(),
+            min_shares,
+        },
+    )?;
+
+    let response = add_address_attribute(
+        Response::new().add_attribute_plaintext("action", "deposit_basket"),
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    );
+    let response =
+        add_address_attribute(response, "depositor", &info.sender, &config.address_privacy);
+    Ok(response.add_message(msg))
+}
+
+pub fn try_cancel_basket(
+    deps: DepsMut,
+    info: MessageInfo,
+    portfolio_snip20: Addr,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    let msg = portfolio_execute_msg(
+        portfolio.contract,
+        &PortfolioExecuteMsg::CancelBasket {
+            depositor: info.sender.clone(),
+        },
+    )?;
+
+    let response = add_address_attribute(
+        Response::new().add_attribute_plaintext("action", "cancel_basket"),
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    );
+    let response =
+        add_address_attribute(response, "depositor", &info.sender, &config.address_privacy);
+    Ok(response.add_message(msg))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn try_create_dca_plan(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    from: Addr,
+    amount: Uint256,
+    portfolio_snip20: Addr,
+    tranches: u32,
+    interval: u64,
+    gate_key: Option<String>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.contract_status != ContractStatus::ACTIVE {
+        return Err(ContractError::DepositsDisabled);
+    }
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    // Checked once, later tranches go in even if access changes
+    check_access(deps.storage, &deps.querier, &portfolio, &from, gate_key)?;
+    let deposit_token = load_deposit_token(deps.storage, &portfolio_snip20, &info.sender)?;
+    if tranches == 0 || interval == 0 {
+        return Err(ContractError::InvalidConfig(
+            "tranches and interval must be greater than zero".to_string(),
+        ));
+    }
+    let amount = Uint128::try_from(amount)?;
+    let tranche_amount = amount.multiply_ratio(1u128, tranches as u128);
+    if tranche_amount.is_zero() {
+        return Err(ContractError::InvalidAmount(
+            "amount is too small to split".to_string(),
+        ));
+    }
+
+    let id = DCA_PLAN_COUNT.may_load(deps.storage)?.unwrap_or(0);
+    DCA_PLAN_COUNT.save(deps.storage, &(id + 1))?;
+    let plan = DcaPlan {
+        id,
+        owner: from.clone(),
+        portfolio_snip20: portfolio_snip20.clone(),
+        deposit_token,
+        tranche_amount,
+        remaining_tranches: tranches,
+        remaining_amount: amount,
+        interval,
+        next_time: env.block.time.seconds(),
+    };
+    DCA_PLANS.insert(deps.storage, &id, &plan)?;
+    USER_DCA_PLANS
+        .add_suffix(from.as_bytes())
+        .insert(deps.storage, &id, &true)?;
+    let token_plans = DCA_TOKEN_PLANS
+        .get(deps.storage, &plan.deposit_token.address)
+        .unwrap_or(0);
+    DCA_TOKEN_PLANS.insert(
+        deps.storage,
+        &plan.deposit_token.address,
+        &(token_plans + 1),
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "create_dca_plan")
+        .add_attribute_plaintext("plan_id", id.to_string())
+        .add_attribute_plaintext("asset", &info.sender)
+        .add_attribute_plaintext("amount", amount.to_string())
+        .add_attribute_plaintext("tranches", tranches.to_string());
+    let response = add_address_attribute(
+        response,
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    );
+    Ok(add_address_attribute(
+        response,
+        "depositor",
+        &from,
+        &config.address_privacy,
+    ))
+}
+
+pub fn try_execute_dca(
+    deps: DepsMut,
+    env: Env,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.contract_status != ContractStatus::ACTIVE {
+        return Err(ContractError::DepositsDisabled);
+    }
+    let now = env.block.time.seconds();
+    let limit = limit.unwrap_or(u32::MAX) as usize;
+    // Plan ids are sequential, check at most MAX_DCA_SCAN of them from where the last call stopped
+    let plan_count = DCA_PLAN_COUNT.may_load(deps.storage)?.unwrap_or(0);
+    let cursor = DCA_CURSOR.may_load(deps.storage)?.unwrap_or(0);
+    let mut due = vec![];
+    let mut scanned = 0;
+    while scanned < plan_count.min(MAX_DCA_SCAN) && due.len() < limit {
+        let id = (cursor + scanned) % plan_count;
+        scanned += 1;
+        if let Some(plan) = DCA_PLANS.get(deps.storage, &id) {
+            if plan.next_time <= now {
+                due.push(plan);
+            }
+        }
+    }
+    if plan_count > 0 {
+        DCA_CURSOR.save(deps.storage, &((cursor + scanned) % plan_count))?;
+    }
+
+    let mut messages = vec![];
+    let mut events = vec![];
+    for mut plan in due {
+        let amount = if plan.remaining_tranches <= 1 {
+            plan.remaining_amount
+        } else {
+            plan.tranche_amount.min(plan.remaining_amount)
+        };
+        let portfolio = load_portfolio(deps.storage, &plan.portfolio_snip20)?;
+        messages.push(portfolio_deposit_msg(
+            &portfolio,
+            &plan.deposit_token,
+            amount,
+            plan.owner.clone(),
+        )?);
+        plan.remaining_tranches = plan.remaining_tranches.saturating_sub(1);
+        plan.remaining_amount = plan.remaining_amount.saturating_sub(amount);
+        plan.next_time = plan.next_time.saturating_add(plan.interval).max(now);
+        if plan.remaining_tranches == 0 || plan.remaining_amount.is_zero() {
+            remove_dca_plan(deps.storage, &plan)?;
+        } else {
+            DCA_PLANS.insert(deps.storage, &plan.id, &plan)?;
+        }
+        events.push(
+            Event::new("dca_tranche")
+                .add_attribute_plaintext("plan_id", plan.id.to_string())
+                .add_attribute_plaintext("amount", amount.to_string())
+                .add_attribute_plaintext("remaining_tranches", plan.remaining_tranches.to_string()),
+        );
+    }
+
+    Ok(Response::new()
+        .add_attribute_plaintext("action", "execute_dca")
+        .add_attribute_plaintext("tranches", events.len().to_string())
+        .add_events(events)
+        .add_messages(messages))
+}
+
+pub fn try_cancel_dca(
+    deps: DepsMut,
+    info: MessageInfo,
+    plan_id: u64,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let plan = DCA_PLANS
+        .get(deps.storage, &plan_id)
+        .ok_or_else(|| ContractError::NotFound("DCA plan".to_string()))?;
+    if plan.owner != info.sender {
+        return Err(ContractError::Unauthorized("plan owner".to_string()));
+    }
+    remove_dca_plan(deps.storage, &plan)?;
+    let msg = snip20::transfer_msg(
+        plan.owner.clone().into_string(),
+        plan.remaining_amount,
+        None,
+        None,
+        BLOCK_SIZE,
+        plan.deposit_token.code_hash.clone(),
+        plan.deposit_token.address.clone().into_string(),
+    )?;
+
+    let response = Response::new()
+        .add_attribute_plaintext("action", "cancel_dca")
+        .add_attribute_plaintext("plan_id", plan_id.to_string())
+        .add_attribute_plaintext("amount", plan.remaining_amount.to_string());
+    Ok(
+        add_address_attribute(response, "depositor", &plan.owner, &config.address_privacy)
+            .add_message(msg),
+    )
+}
+
+pub fn try_set_viewing_key(
+    deps: DepsMut,
+    info: MessageInfo,
+    key: String,
+) -> Result<Response, ContractError> {
+    ViewingKey::set(deps.storage, info.sender.as_str(), &key);
+    Ok(Response::new().add_attribute_plaintext("action", "set_viewing_key"))
+}
+
+pub fn try_set_portfolio_access(
+    deps: DepsMut,
+    info: MessageInfo,
+    portfolio_snip20: Addr,
+    access: PortfolioAccess,
+    permit_queries: bool,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    if info.sender != portfolio.creator {
+        return Err(ContractError::Unauthorized("portfolio creator".to_string()));
+    }
+    if let PortfolioAccess::TokenGated { min_balance, .. } = &access {
+        if min_balance.is_zero() {
+            return Err(ContractError::InvalidConfig(
+                "minimum balance must be greater than zero".to_string(),
+            ));
+        }
+    }
+    PORTFOLIO_ACCESS.insert(deps.storage, &portfolio_snip20, &access)?;
+    PERMIT_QUERIES.insert(deps.storage, &portfolio_snip20, &permit_queries)?;
+    let msg = portfolio_execute_msg(
+        portfolio.contract,
+        &PortfolioExecuteMsg::SetPermitQueries {
+            enabled: permit_queries,
+        },
+    )?;
+
+    let mode = match access {
+        PortfolioAccess::Public => "public",
+        PortfolioAccess::Whitelist => "whitelist",
+        PortfolioAccess::TokenGated { .. } => "token_gated",
+    };
+    let response = Response::new()
+        .add_attribute_plaintext("action", "set_portfolio_access")
+        .add_attribute_plaintext("access", mode)
+        .add_attribute_plaintext("permit_queries", permit_queries.to_string());
+    Ok(add_address_attribute(
+        response,
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    )
+    .add_message(msg))
+}
+
+pub fn try_update_allowlist(
+    deps: DepsMut,
+    info: MessageInfo,
+    portfolio_snip20: Addr,
+    add: Vec<Addr>,
+    remove: Vec<Addr>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    if info.sender != portfolio.creator {
+        return Err(ContractError::Unauthorized("portfolio creator".to_string()));
+    }
+    let allowlist = ALLOWLIST.add_suffix(portfolio_snip20.as_bytes());
+    for address in &add {
+        allowlist.insert(deps.storage, address, &true)?;
+    }
+    for address in &remove {
+        allowlist.remove(deps.storage, address)?;
+    }
+
+    // Only counts are emitted, the allowlist itself stays private
+    let response = Response::new()
+        .add_attribute_plaintext("action", "update_allowlist")
+        .add_attribute_plaintext("added", add.len().to_string())
+        .add_attribute_plaintext("removed", remove.len().to_string());
+    Ok(add_address_attribute(
+        response,
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    ))
+}
+
+pub fn try_set_portfolio_limits(
+    deps: DepsMut,
+    info: MessageInfo,
+    portfolio_snip20: Addr,
+    limits: PortfolioLimits,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    if info.sender != portfolio.creator {
+        return Err(ContractError::Unauthorized("portfolio creator".to_string()));
+    }
+    let bounds = &config.limit_bounds;
+    if !within_bound(limits.tvl_cap, bounds.max_tvl_cap) {
+        return Err(ContractError::InvalidConfig(
+            "TVL cap is above the factory bound".to_string(),
+        ));
+    }
+    if !within_bound(limits.user_cap, bounds.max_user_cap) {
+        return Err(ContractError::InvalidConfig(
+            "user cap is above the factory bound".to_string(),
+        ));
+    }
+    if !within_bound(limits.min_deposit, bounds.max_min_deposit) {
+        return Err(ContractError::InvalidConfig(
+            "minimum deposit is above the factory bound".to_string(),
+        ));
+    }
+    if !within_bound(limits.min_withdraw, bounds.max_min_withdraw) {
+        return Err(ContractError::InvalidConfig(
+            "minimum withdraw is above the factory bound".to_string(),
+        ));
+    }
+    if matches!((limits.min_deposit, limits.user_cap), (Some(min), Some(cap)) if min > cap) {
+        return Err(ContractError::InvalidConfig(
+            "minimum deposit must not exceed the user cap".to_string(),
+        ));
+    }
+    PORTFOLIO_LIMITS.insert(deps.storage, &portfolio_snip20, &limits)?;
+    let msg = portfolio_execute_msg(
+        portfolio.contract,
+        &PortfolioExecuteMsg::SetLimits {
+            limits: limits.clone(),
+        },
+    )?;
+
+    let mut response = Response::new().add_attribute_plaintext("action", "set_portfolio_limits");
+    if let Some(tvl_cap) = limits.tvl_cap {
+        response = response.add_attribute_plaintext("tvl_cap", tvl_cap.to_string());
+    }
+    if let Some(user_cap) = limits.user_cap {
+        response = response.add_attribute_plaintext("user_cap", user_cap.to_string());
+    }
+    if let Some(min_deposit) = limits.min_deposit {
+        response = response.add_attribute_plaintext("min_deposit", min_deposit.to_string());
+    }
+    if let Some(min_withdraw) = limits.min_withdraw {
+        response = response.add_attribute_plaintext("min_withdraw", min_withdraw.to_string());
+    }
+    Ok(add_address_attribute(
+        response,
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    )
+    .add_message(msg))
+}
+
+pub fn try_set_withdraw_queue(
+    deps: DepsMut,
+    info: MessageInfo,
+    portfolio_snip20: Addr,
+    queue: Option<WithdrawQueueConfig>,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    if info.sender != portfolio.creator {
+        return Err(ContractError::Unauthorized("portfolio creator".to_string()));
+    }
+    // Requests already queued keep their release time
+    let response = match queue {
+        Some(queue) => {
+            if matches!(queue.release, QueueRelease::Epoch { length: 0 }) {
+                return Err(ContractError::InvalidConfig(
+                    "epoch length must be greater than zero".to_string(),
+                ));
+            }
+            WITHDRAW_QUEUES.insert(deps.storage, &portfolio_snip20, &queue)?;
+            Response::new()
+                .add_attribute_plaintext("action", "set_withdraw_queue")
+                .add_attribute_plaintext("threshold", queue.threshold.to_string())
+        }
+        None => {
+            WITHDRAW_QUEUES.remove(deps.storage, &portfolio_snip20)?;
+            Response::new().add_attribute_plaintext("action", "set_withdraw_queue")
+        }
+    };
+    Ok(add_address_attribute(
+        response,
+        "portfolio",
+        &portfolio_snip20,
+        &config.address_privacy,
+    ))
+}
+
+fn within_bound<T: PartialOrd>(value: Option<T>, bound: Option<T>) -> bool {
+    match (value, bound) {
+        (Some(value), Some(bound)) => value <= bound,
+        _ => true,
+    }
+}
+
+fn remove_dca_plan(storage: &mut dyn Storage, plan: &DcaPlan) -> StdResult<()> {
+    DCA_PLANS.remove(storage, &plan.id)?;
+    USER_DCA_PLANS
+        .add_suffix(plan.owner.as_bytes())
+        .remove(storage, &plan.id)?;
+    match DCA_TOKEN_PLANS.get(storage, &plan.deposit_token.address) {
+        Some(count) if count > 1 => {
+            DCA_TOKEN_PLANS.insert(storage, &plan.deposit_token.address, &(count - 1))
+        }
+        _ => DCA_TOKEN_PLANS.remove(storage, &plan.deposit_token.address),
+    }
+}
+
+// Sends a deposit to the portfolio, which mints shares to the depositor
+pub fn portfolio_deposit_msg(
+    portfolio: &Portfolio,
+    deposit_token: &ContractInfo,
+    amount: Uint128,
+    depositor: Addr,
+) -> StdResult<CosmosMsg> {
+    snip20::send_msg_with_code_hash(
+        portfolio.contract.address.clone().into_string(),
+        Some(portfolio.contract.code_hash.clone()),
+        amount,
+        Some(to_binary(&PortfolioReceiveMsg::Deposit { depositor })?),
+        None,
+        None,
+        BLOCK_SIZE,
+        deposit_token.code_hash.clone(),
+        deposit_token.address.clone().into_string(),
+    )
+}
+
+pub fn load_deposit_token(
+    storage: &dyn Storage,
+    portfolio_snip20: &Addr,
+    token: &Addr,
+) -> Result<ContractInfo, ContractError> {
+    DEPOSIT_TOKENS
+        .get(storage, portfolio_snip20)
+        .unwrap_or_default()
+        .into_iter()
+        .find(|x| &x.address == token)
+        .ok_or_else(|| ContractError::InvalidToken("must be a valid deposit token".to_string()))
+}
+
+pub fn load_portfolio(
+    storage: &dyn Storage,
+    portfolio_snip20: &Addr,
+) -> Result<Portfolio, ContractError> {
+    PORTFOLIO
+        .get(storage, portfolio_snip20)
+        .ok_or_else(|| ContractError::NotFound("portfolio".to_string()))
+}
+
+// Walks the portfolio shares held by config, rejecting cycles and deep nesting
+// path starts with the portfolio being checked, so holding its own shares is a cycle too
+pub fn check_nesting(
+    storage: &dyn Storage,
+    config: &[PortfolioConfig],
+    path: &mut Vec<Addr>,
+) -> Result<(), ContractError> {
+    for position in config {
+        if let Some(nested) = PORTFOLIO.get(storage, &position.asset.address) {
+            if path.contains(&position.asset.address) {
+                return Err(ContractError::InvalidWeights(
+                    "portfolio nesting must not form a cycle".to_string(),
+                ));
+            }
+            if path.len() as u32 > MAX_NESTING_DEPTH {
+                return Err(ContractError::InvalidWeights(
+                    "portfolios are nested too deeply".to_string(),
+                ));
+            }
+            path.push(position.asset.address.clone());
+            check_nesting(storage, &nested.config, path)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+// Non public portfolios take deposits from the creator, allowlisted addresses, registered
+// portfolios holding them in their weights, and for token-gated ones holders of the gate token
+pub fn check_access(
+    storage: &dyn Storage,
+    querier: &QuerierWrapper,
+    portfolio: &Portfolio,
+    depositor: &Addr,
+    gate_key: Option<String>,
+) -> Result<(), ContractError> {
+    if is_allowed(storage, portfolio, depositor) {
+        return Ok(());
+    }
+    // Nested parents deposit through their routes, which carry no gate key
+    if let Some(parent) = PORTFOLIO_CONTRACTS
+        .get(storage, depositor)
+        .and_then(|x| PORTFOLIO.get(storage, &x))
+    {
+        if parent
+            .config
+            .iter()
+            .any(|x| x.asset.address == portfolio.snip20.address)
+        {
+            return Ok(());
+        }
+    }
+    if let Some(PortfolioAccess::TokenGated { token, min_balance }) =
+        PORTFOLIO_ACCESS.get(storage, &portfolio.snip20.address)
+    {
+        let key = gate_key.ok_or_else(|| {
+            ContractError::AccessDenied("a viewing key for the gate token is required".to_string())
+        })?;
+        let balance = snip20::balance_query(
+            *querier,
+            depositor.to_string(),
+            key,
+            BLOCK_SIZE,
+            token.code_hash,
+            token.address.into_string(),
+        )?;
+        if balance.amount >= min_balance {
+            return Ok(());
+        }
+    }
+    Err(ContractError::AccessDenied(
+        "depositor is not allowed in this portfolio".to_string(),
+    ))
+}
+
+// Access without the gate token
+fn is_allowed(storage: &dyn Storage, portfolio: &Portfolio, address: &Addr) -> bool {
+    match PORTFOLIO_ACCESS
+        .get(storage, &portfolio.snip20.address)
+        .unwrap_or_default()
+    {
+        PortfolioAccess::Public => true,
+        _ => {
+            address == portfolio.creator
+                || ALLOWLIST
+                    .add_suffix(portfolio.snip20.address.as_bytes())
+                    .contains(storage, address)
+        }
+    }
+}
+
+fn check_viewing_key(storage: &dyn Storage, key: &str) -> Result<(), ContractError> {
+    if VIEWING_KEY.load(storage)? != key {
+        return Err(ContractError::InvalidViewingKey);
+    }
+    Ok(())
 }
 
 #[entry_point]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> Result<Binary, ContractError> {
+    let response = match msg {
         QueryMsg::GetConfig {} => to_binary(&get_config(deps)?),
         QueryMsg::GetState {} => to_binary(&get_config(deps)?),
         QueryMsg::GetUnupdated {} => to_binary(&get_config(deps)?),
-        QueryMsg::Prices { .. } => to_binary(&get_config(deps)?),
-        QueryMsg::Route { .. } => to_binary(&get_config(deps)?),
-    }
+        QueryMsg::Prices { assets, key } => to_binary(&get_prices(deps, env, assets, key)?),
+        QueryMsg::Route { route, key } => to_binary(&get_route(deps, env, route, key)?),
+        QueryMsg::Nav { portfolio_snip20 } => to_binary(&get_nav(deps, portfolio_snip20)?),
+        QueryMsg::DepositTokens { page, page_size } => {
+            to_binary(&get_deposit_tokens(deps, page, page_size)?)
+        }
+        QueryMsg::Supplies { assets } => to_binary(&get_supplies(deps, assets)?),
+        QueryMsg::DcaPlans {
+            address,
+            key,
+            page,
+            page_size,
+        } => to_binary(&get_dca_plans(deps, address, key, page, page_size)?),
+        QueryMsg::PortfolioAccess { portfolio_snip20 } => {
+            to_binary(&get_portfolio_access(deps, portfolio_snip20)?)
+        }
+        QueryMsg::Capacity {
+            portfolio_snip20,
+            address,
+            key,
+        } => to_binary(&get_capacity(deps, portfolio_snip20, address, key)?),
+        QueryMsg::StrayTokens {} => to_binary(&get_stray_tokens(deps, env)?),
+        QueryMsg::WithdrawQueue { portfolio_snip20 } => {
+            to_binary(&get_withdraw_queue(deps, portfolio_snip20)?)
+        }
+        QueryMsg::QueuedWithdraws {
+            address,
+            key,
+            page,
+            page_size,
+        } => to_binary(&get_queued_withdraws(deps, address, key, page, page_size)?),
+        QueryMsg::IsAllowed {
+            portfolio_snip20,
+            address,
+            key,
+        } => to_binary(&get_is_allowed(deps, portfolio_snip20, address, key)?),
+        QueryMsg::Keepers { page, page_size } => {
+            to_binary(&get_keepers(deps, env, page, page_size)?)
+        }
+        QueryMsg::CheckNesting {
+            portfolio_snip20,
+            config,
+        } => to_binary(&get_check_nesting(deps, portfolio_snip20, config)?),
+        QueryMsg::NeedsRebalance { page, page_size } => {
+            to_binary(&get_needs_rebalance(deps, env, page, page_size)?)
+        }
+    };
+    Ok(response?)
 }
 
-fn get_config(deps: Deps) -> StdResult<Config> {
+fn get_config(deps: Deps) -> Result<Config, ContractError> {
     let state = CONFIG.load(deps.storage)?;
     Ok(state)
 }
+
+// Registered assets are priced from SetPrices, an asset without a price set within
+// max_price_age is stale
+fn get_prices(
+    deps: Deps,
+    env: Env,
+    assets: Vec<Addr>,
+    key: String,
+) -> Result<QueryAnswer, ContractError> {
+    check_viewing_key(deps.storage, &key)?;
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    let mut prices = vec![];
+    for asset in assets {
+        // Shares are worth their slice of the portfolio, which prices its own assets here too
+        if let Some(portfolio) = PORTFOLIO.get(deps.storage, &asset) {
+            let nav = query_portfolio_nav(&deps.querier, portfolio.contract, Some(key.clone()))?;
+            let token_info = snip20::token_info_query(
+                deps.querier,
+                BLOCK_SIZE,
+                portfolio.snip20.code_hash,
+                portfolio.snip20.address.into_string(),
+            )?;
+            prices.push(Price {
+                asset,
+                price: Uint128::try_from(nav.nav_per_share)?,
+                decimals: token_info.decimals,
+            });
+            continue;
+        }
+        match (
+            ASSET_PRICES.get(deps.storage, &asset),
+            ASSET_DECIMALS.get(deps.storage, &asset),
+        ) {
+            (Some(price), Some(decimals))
+                if now.saturating_sub(price.updated) <= config.max_price_age =>
+            {
+                prices.push(Price {
+                    asset,
+                    price: price.price,
+                    decimals,
+                })
+            }
+            _ => return Err(ContractError::StalePrice(asset.into_string())),
+        }
+    }
+    Ok(QueryAnswer::Prices { prices })
+}
+
+fn get_route(
+    deps: Deps,
+    env: Env,
+    key: RouteKey,
+    viewing_key: String,
+) -> Result<QueryAnswer, ContractError> {
+    check_viewing_key(deps.storage, &viewing_key)?;
+    let from_portfolio = PORTFOLIO.contains(deps.storage, &key.0);
+    let to_portfolio = PORTFOLIO.contains(deps.storage, &key.1);
+    let route = match (from_portfolio, to_portfolio) {
+        (true, true) => {
+            return Err(ContractError::RouteNotFound(
+                "no route between two portfolio shares".to_string(),
+            ))
+        }
+        (false, true) => {
+            load_deposit_token(deps.storage, &key.1, &key.0)?;
+            Route {
+                key,
+                route: vec![],
+                router_contract: env.contract,
+                kind: RouteKind::Deposit,
+            }
+        }
+        (true, false) => Route {
+            key,
+            route: vec![],
+            router_contract: env.contract,
+            kind: RouteKind::Withdraw,
+        },
+        (false, false) => {
+            let config = CONFIG.load(deps.storage)?;
+            let route = ROUTE_CACHE
+                .get(deps.storage, &key)
+                .ok_or_else(|| ContractError::RouteNotFound("no cached route".to_string()))?;
+            Route {
+                key,
+                route,
+                router_contract: config.swap_factory,
+                kind: RouteKind::Swap,
+            }
+        }
+    };
+    Ok(QueryAnswer::Route { route })
+}
+
+fn get_nav(deps: Deps, portfolio_snip20: Addr) -> Result<QueryAnswer, ContractError> {
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    if PERMIT_QUERIES
+        .get(deps.storage, &portfolio_snip20)
+        .unwrap_or(false)
+    {
+        return Err(ContractError::PermitRequired);
+    }
+    let nav = query_portfolio_nav(&deps.querier, portfolio.contract, None)?;
+    Ok(QueryAnswer::Nav { nav })
+}
+
+fn get_portfolio_access(deps: Deps, portfolio_snip20: Addr) -> Result<QueryAnswer, ContractError> {
+    load_portfolio(deps.storage, &portfolio_snip20)?;
+    Ok(QueryAnswer::PortfolioAccess {
+        access: PORTFOLIO_ACCESS
+            .get(deps.storage, &portfolio_snip20)
+            .unwrap_or_default(),
+        permit_queries: PERMIT_QUERIES
+            .get(deps.storage, &portfolio_snip20)
+            .unwrap_or(false),
+    })
+}
+
+fn get_capacity(
+    deps: Deps,
+    portfolio_snip20: Addr,
+    address: Option<Addr>,
+    key: Option<String>,
+) -> Result<QueryAnswer, ContractError> {
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    // The remaining TVL reveals the portfolio's value, closed like Nav
+    if address.is_none()
+        && PERMIT_QUERIES
+            .get(deps.storage, &portfolio_snip20)
+            .unwrap_or(false)
+    {
+        return Err(ContractError::PermitRequired);
+    }
+    // The portfolio trusts the factory's key, so the user's own key is checked here
+    let portfolio_key = match &address {
+        Some(address) => {
+            let key = key.ok_or(ContractError::InvalidViewingKey)?;
+            ViewingKey::check(deps.storage, address.as_str(), &key)
+                .map_err(|_| ContractError::InvalidViewingKey)?;
+            Some(VIEWING_KEY.load(deps.storage)?)
+        }
+        None => None,
+    };
+    let capacity =
+        query_portfolio_capacity(&deps.querier, portfolio.contract, address, portfolio_key)?;
+    Ok(QueryAnswer::Capacity { capacity })
+}
+
+fn get_stray_tokens(deps: Deps, env: Env) -> Result<QueryAnswer, ContractError> {
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let mut tokens = vec![];
+    for token in STRAY_TOKENS.iter(deps.storage)? {
+        let (_, token) = token?;
+        let balance = snip20::balance_query(
+            deps.querier,
+            env.contract.address.clone().into_string(),
+            viewing_key.clone(),
+            BLOCK_SIZE,
+            token.code_hash,
+            token.address.clone().into_string(),
+        )?;
+        tokens.push(TokenBalance {
+            token: token.address,
+            amount: balance.amount,
+        });
+    }
+    Ok(QueryAnswer::StrayTokens { tokens })
+}
+
+fn get_withdraw_queue(deps: Deps, portfolio_snip20: Addr) -> Result<QueryAnswer, ContractError> {
+    load_portfolio(deps.storage, &portfolio_snip20)?;
+    Ok(QueryAnswer::WithdrawQueue {
+        queue: WITHDRAW_QUEUES.get(deps.storage, &portfolio_snip20),
+        total_shares: QUEUED_SHARES
+            .get(deps.storage, &portfolio_snip20)
+            .unwrap_or(Uint128::zero()),
+    })
+}
+
+fn get_queued_withdraws(
+    deps: Deps,
+    address: Addr,
+    key: String,
+    page: u32,
+    page_size: u32,
+) -> Result<QueryAnswer, ContractError> {
+    ViewingKey::check(deps.storage, address.as_str(), &key)
+        .map_err(|_| ContractError::InvalidViewingKey)?;
+    let user_requests = USER_QUEUED_WITHDRAWS.add_suffix(address.as_bytes());
+    let requests = user_requests
+        .paging_keys(deps.storage, page, page_size)?
+        .into_iter()
+        .filter_map(|id| QUEUED_WITHDRAWS.get(deps.storage, &id))
+        .collect();
+    Ok(QueryAnswer::QueuedWithdraws {
+        requests,
+        total: user_requests.get_len(deps.storage)?,
+    })
+}
+
+fn get_is_allowed(
+    deps: Deps,
+    portfolio_snip20: Addr,
+    address: Addr,
+    key: String,
+) -> Result<QueryAnswer, ContractError> {
+    check_viewing_key(deps.storage, &key)?;
+    let portfolio = load_portfolio(deps.storage, &portfolio_snip20)?;
+    Ok(QueryAnswer::IsAllowed {
+        allowed: is_allowed(deps.storage, &portfolio, &address),
+    })
+}
+
+fn get_check_nesting(
+    deps: Deps,
+    portfolio_snip20: Addr,
+    config: Vec<PortfolioConfig>,
+) -> Result<QueryAnswer, ContractError> {
+    load_portfolio(deps.storage, &portfolio_snip20)?;
+    let error = match check_nesting(deps.storage, &config, &mut vec![portfolio_snip20]) {
+        Ok(()) => None,
+        Err(ContractError::InvalidWeights(reason)) => Some(reason),
+        Err(err) => return Err(err),
+    };
+    Ok(QueryAnswer::CheckNesting { error })
+}
+
+fn get_dca_plans(
+    deps: Deps,
+    address: Addr,
+    key: String,
+    page: u32,
+    page_size: u32,
+) -> Result<QueryAnswer, ContractError> {
+    ViewingKey::check(deps.storage, address.as_str(), &key)
+        .map_err(|_| ContractError::InvalidViewingKey)?;
+    let user_plans = USER_DCA_PLANS.add_suffix(address.as_bytes());
+    let plans = user_plans
+        .paging_keys(deps.storage, page, page_size)?
+        .into_iter()
+        .filter_map(|id| DCA_PLANS.get(deps.storage, &id))
+        .collect();
+    Ok(QueryAnswer::DcaPlans {
+        plans,
+        total: user_plans.get_len(deps.storage)?,
+    })
+}
+
+fn get_supplies(deps: Deps, assets: Vec<ContractInfo>) -> Result<QueryAnswer, ContractError> {
+    let mut supplies = vec![];
+    for asset in assets {
+        let token_info = snip20::token_info_query(
+            deps.querier,
+            BLOCK_SIZE,
+            asset.code_hash,
+            asset.address.clone().into_string(),
+        )?;
+        supplies.push(Supply {
+            asset: asset.address,
+            total_supply: token_info.total_supply.ok_or_else(|| {
+                ContractError::InvalidToken("token supply is private".to_string())
+            })?,
+        });
+    }
+    Ok(QueryAnswer::Supplies { supplies })
+}
+
+fn get_keepers(
+    deps: Deps,
+    env: Env,
+    page: u32,
+    page_size: u32,
+) -> Result<QueryAnswer, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let keepers = KEEPERS
+        .paging(deps.storage, page, page_size)?
+        .into_iter()
+        .map(|(keeper, stats)| KeeperInfo { keeper, stats })
+        .collect();
+    let epoch_paid = match &config.keeper_reward {
+        Some(keeper_reward) => {
+            let epoch = env.block.time.seconds() / keeper_reward.epoch_length.max(1);
+            REWARD_EPOCH
+                .may_load(deps.storage)?
+                .filter(|x| x.epoch == epoch)
+                .map(|x| x.paid)
+                .unwrap_or_default()
+        }
+        None => Uint128::zero(),
+    };
+    Ok(QueryAnswer::Keepers {
+        keepers,
+        total: KEEPERS.get_len(deps.storage)?,
+        reward: config.keeper_reward,
+        pool: KEEPER_POOL.may_load(deps.storage)?.unwrap_or_default(),
+        epoch_paid,
+    })
+}
+
+// Permit gated portfolios are left out, their weights are private
+fn get_needs_rebalance(
+    deps: Deps,
+    env: Env,
+    page: u32,
+    page_size: u32,
+) -> Result<QueryAnswer, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let tolerance_percent = config
+        .tolerance_percent
+        .unwrap_or(DEFAULT_TOLERANCE_PERCENT);
+    let viewing_key = VIEWING_KEY.load(deps.storage)?;
+    let portfolio_list = PORTFOLIO_LIST.load(deps.storage)?;
+    let page_size = page_size.min(MAX_PAGE_SIZE) as usize;
+    // Portfolios behind permits are listed too, the crank updates them with the factory's key
+    let portfolios = portfolio_list
+        .iter()
+        .skip((page as usize).saturating_mul(page_size))
+        .take(page_size)
+        .filter_map(|portfolio_snip20| {
+            update_candidate(
+                deps,
+                &env,
+                &config,
+                portfolio_snip20,
+                tolerance_percent,
+                &viewing_key,
+            )
+            .ok()
+            .flatten()
+        })
+        .collect();
+    Ok(QueryAnswer::NeedsRebalance {
+        portfolios,
+        total: portfolio_list.len() as u32,
+    })
+}
+
+fn get_deposit_tokens(deps: Deps, page: u32, page_size: u32) -> Result<QueryAnswer, ContractError> {
+    let portfolios = DEPOSIT_TOKENS
+        .paging(deps.storage, page, page_size)?
+        .into_iter()
+        .map(
+            |(portfolio_snip20, deposit_tokens)| PortfolioDepositTokens {
+                portfolio_snip20,
+                deposit_tokens,
+            },
+        )
+        .collect();
+    Ok(QueryAnswer::DepositTokens {
+        portfolios,
+        total: DEPOSIT_TOKENS.get_len(deps.storage)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::{Nav, PortfolioQueryAnswer, PortfolioQueryMsg};
+    use crate::state::KeeperStats;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info, MockQuerier};
+    use cosmwasm_std::{ContractResult, SystemError, SystemResult, WasmQuery};
+
+    const KEY: &str = "factory_key";
+    const ONE_USD: u128 = 1_000_000_000_000_000_000;
+
+    fn contract_info(address: &str) -> ContractInfo {
+        ContractInfo {
+            address: Addr::unchecked(address),
+            code_hash: format!("{}_hash", address),
+        }
+    }
+
+    fn position(asset: &str, percent: u32) -> PortfolioConfig {
+        PortfolioConfig {
+            percent,
+            asset: contract_info(asset),
+            tolerance: None,
+        }
+    }
+
+    fn portfolio(name: &str, config: Vec<PortfolioConfig>) -> Portfolio {
+        Portfolio {
+            config,
+            creator: Addr::unchecked("creator"),
+            name: name.to_string(),
+            snip20: contract_info(&format!("{}_snip20", name)),
+            contract: contract_info(&format!("{}_contract", name)),
+        }
+    }
+
+    fn setup(deps: DepsMut) {
+        instantiate(
+            deps,
+            mock_env(),
+            mock_info("admin", &[]),
+            InstantiateMsg {
+                admin: Addr::unchecked("admin"),
+                viewing_key: KEY.to_string(),
+                swap_factory: contract_info("swap_factory"),
+                withdraw_fee: Uint128::zero(),
+                create_fee: Uint128::zero(),
+                snip20_code_id: 1,
+                portfolio_code_id: 2,
+                accepted_deposit_tokens: Some(vec![contract_info("usdc")]),
+                address_privacy: None,
+                limit_bounds: None,
+                tolerance_percent: None,
+                keeper_reward: None,
+                update_priority: None,
+                rebalance_interval: None,
+                price_feeder: None,
+                max_price_age: None,
+            },
+        )
+        .unwrap();
+    }
+
+    fn register(deps: DepsMut, portfolio: Portfolio) -> Result<Response, ContractError> {
+        try_register_portfolio(deps, mock_info("admin", &[]), portfolio, None)
+    }
+
+    // Stored the way registration leaves it
+    fn save_portfolio(deps: DepsMut, portfolio: Portfolio) {
+        PORTFOLIO
+            .insert(deps.storage, &portfolio.snip20.address, &portfolio)
+            .unwrap();
+    }
+
+    // Every snip20 has supply tokens of 6 decimals
+    fn mock_supply(querier: &mut MockQuerier, supply: u128) {
+        querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { .. } => SystemResult::Ok(ContractResult::Ok(token_info(supply))),
+            _ => SystemResult::Err(SystemError::UnsupportedRequest {
+                kind: "wasm".to_string(),
+            }),
+        });
+    }
+
+    fn token_info(supply: u128) -> Binary {
+        Binary::from(
+            format!(
+                concat!(
+                    r#"{{"token_info":{{"name":"token","symbol":"TOKEN","#,
+                    r#""decimals":6,"total_supply":"{}"}}}}"#
+                ),
+                supply
+            )
+            .into_bytes(),
+        )
+    }
+
+    // Portfolios are worth nav_per_share for one whole share, anything else is a snip20 with
+    // one whole token of supply
+    fn mock_nav(querier: &mut MockQuerier, nav_per_share: u128) {
+        querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { msg, .. } => {
+                SystemResult::Ok(ContractResult::Ok(nav_answer(msg, nav_per_share)))
+            }
+            _ => SystemResult::Err(SystemError::UnsupportedRequest {
+                kind: "wasm".to_string(),
+            }),
+        });
+    }
+
+    fn nav_answer(msg: &Binary, nav_per_share: u128) -> Binary {
+        match from_binary::<PortfolioQueryMsg>(msg) {
+            Ok(PortfolioQueryMsg::GetNav { .. }) => to_binary(&PortfolioQueryAnswer::GetNav {
+                nav: Nav {
+                    total_value: Uint256::from(nav_per_share),
+                    total_supply: Uint128::new(1_000_000),
+                    nav_per_share: Uint256::from(nav_per_share),
+                },
+            })
+            .unwrap(),
+            _ => token_info(1_000_000),
+        }
+    }
+
+    fn portfolio_msg(msg: &SubMsg) -> PortfolioExecuteMsg {
+        match &msg.msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { msg, .. }) => from_binary(msg).unwrap(),
+            _ => panic!("expected a wasm execute"),
+        }
+    }
+
+    fn withdraw(
+        deps: DepsMut,
+        env: Env,
+        amount: u128,
+        zap_out: Option<ZapOut>,
+    ) -> Result<Response, ContractError> {
+        try_withdraw(
+            deps,
+            env,
+            mock_info("a_snip20", &[]),
+            Addr::unchecked("user"),
+            Addr::unchecked("user"),
+            Uint256::from(amount),
+            zap_out,
+        )
+    }
+
+    // Events of an Update reply where the portfolio made swaps
+    fn swaps(name: &str, swaps: u64) -> Option<Vec<Event>> {
+        Some(vec![Event::new("wasm")
+            .add_attribute_plaintext("contract_address", format!("{}_contract", name))
+            .add_attribute_plaintext(
+                "rebalance_swaps",
+                swaps.to_string(),
+            )])
+    }
+
+    fn weight(drift: u64, over_target: bool) -> WeightItem {
+        WeightItem {
+            asset: Addr::unchecked("asset"),
+            balance: Uint128::zero(),
+            price: Uint128::zero(),
+            value: Uint256::zero(),
+            current_weight: Decimal256::zero(),
+            target_weight: Decimal256::zero(),
+            drift: Decimal256::percent(drift),
+            over_target,
+            rebalance_due: true,
+        }
+    }
+
+    #[test]
+    fn nesting_rejects_cycles_and_deep_portfolios() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        register(deps.as_mut(), portfolio("a", vec![position("usdc", 100)])).unwrap();
+        register(
+            deps.as_mut(),
+            portfolio("b", vec![position("a_snip20", 100)]),
+        )
+        .unwrap();
+        register(
+            deps.as_mut(),
+            portfolio("c", vec![position("b_snip20", 100)]),
+        )
+        .unwrap();
+        register(
+            deps.as_mut(),
+            portfolio("d", vec![position("c_snip20", 100)]),
+        )
+        .unwrap();
+
+        let err = register(
+            deps.as_mut(),
+            portfolio("e", vec![position("d_snip20", 100)]),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::InvalidWeights(_)));
+
+        // a taking b's shares would hold itself through b
+        let err = check_nesting(
+            deps.as_ref().storage,
+            &[position("b_snip20", 100)],
+            &mut vec![Addr::unchecked("a_snip20")],
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::InvalidWeights(_)));
+    }
+
+    #[test]
+    fn prices_come_from_the_feed_and_go_stale() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        register(deps.as_mut(), portfolio("a", vec![position("usdc", 100)])).unwrap();
+        mock_nav(&mut deps.querier, 3 * ONE_USD);
+        let usdc = Addr::unchecked("usdc");
+        let set_price = |asset: &Addr| {
+            vec![SetPrice {
+                asset: asset.clone(),
+                price: Uint128::new(ONE_USD),
+            }]
+        };
+
+        let err = try_set_prices(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("user", &[]),
+            set_price(&usdc),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized(_)));
+        let err = try_set_prices(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            set_price(&usdc),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::InvalidToken(_)));
+        try_register_assets(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            vec![contract_info("usdc")],
+        )
+        .unwrap();
+        try_set_prices(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            set_price(&usdc),
+        )
+        .unwrap();
+
+        let assets = vec![usdc.clone(), Addr::unchecked("a_snip20")];
+        let answer =
+            get_prices(deps.as_ref(), mock_env(), assets.clone(), KEY.to_string()).unwrap();
+        assert_eq!(
+            answer,
+            QueryAnswer::Prices {
+                prices: vec![
+                    Price {
+                        asset: usdc,
+                        price: Uint128::new(ONE_USD),
+                        decimals: 6,
+                    },
+                    Price {
+                        asset: Addr::unchecked("a_snip20"),
+                        price: Uint128::new(3 * ONE_USD),
+                        decimals: 6,
+                    },
+                ],
+            }
+        );
+
+        let mut later = mock_env();
+        later.block.time = later.block.time.plus_seconds(DEFAULT_MAX_PRICE_AGE + 1);
+        let err = get_prices(deps.as_ref(), later, assets, KEY.to_string()).unwrap_err();
+        assert!(matches!(err, ContractError::StalePrice(_)));
+    }
+
+    #[test]
+    fn withdraw_pays_out_share_and_burns() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        save_portfolio(deps.as_mut(), portfolio("a", vec![position("usdc", 100)]));
+        mock_supply(&mut deps.querier, 1_000);
+
+        let zap_out = ZapOut {
+            asset: contract_info("usdc"),
+            min_output: Uint128::new(1),
+        };
+        let response = withdraw(deps.as_mut(), mock_env(), 250, Some(zap_out.clone())).unwrap();
+        assert_eq!(response.messages.len(), 2);
+        assert_eq!(
+            portfolio_msg(&response.messages[0]),
+            PortfolioExecuteMsg::Withdraw {
+                share: Uint128::new(10).pow(NORMALIZATION_FACTOR) / Uint128::new(4),
+                receiver: Addr::unchecked("user"),
+                fee: 0,
+                zap_out: Some(zap_out),
+            }
+        );
+    }
+
+    #[test]
+    fn withdraws_over_the_window_threshold_are_queued() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        save_portfolio(deps.as_mut(), portfolio("a", vec![position("usdc", 100)]));
+        mock_supply(&mut deps.querier, 1_000);
+        WITHDRAW_QUEUES
+            .insert(
+                deps.as_mut().storage,
+                &Addr::unchecked("a_snip20"),
+                &WithdrawQueueConfig {
+                    threshold: Uint128::new(100),
+                    release: QueueRelease::Cooldown { seconds: 3600 },
+                },
+            )
+            .unwrap();
+        let env = mock_env();
+
+        let response = withdraw(deps.as_mut(), env.clone(), 60, None).unwrap();
+        assert_eq!(response.messages.len(), 2);
+
+        // Under the threshold alone, over it with what was already withdrawn this window
+        let zap_out = ZapOut {
+            asset: contract_info("atom"),
+            min_output: Uint128::new(1),
+        };
+        let err = withdraw(deps.as_mut(), env.clone(), 60, Some(zap_out)).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidToken(_)));
+        let response = withdraw(deps.as_mut(), env.clone(), 60, None).unwrap();
+        assert!(response.messages.is_empty());
+        let request = QUEUED_WITHDRAWS.get(deps.as_ref().storage, &0).unwrap();
+        assert_eq!(request.amount, Uint128::new(60));
+        assert_eq!(request.ready_at, env.block.time.seconds() + 3600);
+
+        let response = try_process_withdraws(deps.as_mut(), env.clone(), None).unwrap();
+        assert!(response.messages.is_empty());
+
+        let mut later = env.clone();
+        later.block.time = later.block.time.plus_seconds(3600);
+        let response = try_process_withdraws(deps.as_mut(), later.clone(), None).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        assert_eq!(response.messages[0].id, RELEASE_WITHDRAW_REPLY_ID);
+
+        let err = try_release_withdraw(deps.as_mut(), later.clone(), mock_info("user", &[]), 0)
+            .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized(_)));
+        let contract = later.contract.address.to_string();
+        let response =
+            try_release_withdraw(deps.as_mut(), later, mock_info(&contract, &[]), 0).unwrap();
+        assert_eq!(response.messages.len(), 2);
+        assert!(QUEUED_WITHDRAWS.get(deps.as_ref().storage, &0).is_none());
+        assert_eq!(
+            QUEUED_SHARES.get(deps.as_ref().storage, &Addr::unchecked("a_snip20")),
+            Some(Uint128::zero())
+        );
+    }
+
+    #[test]
+    fn protected_withdraws_skip_the_minimum_and_queue() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        save_portfolio(deps.as_mut(), portfolio("a", vec![position("usdc", 100)]));
+        mock_supply(&mut deps.querier, 1_000);
+        PORTFOLIO_LIMITS
+            .insert(
+                deps.as_mut().storage,
+                &Addr::unchecked("a_snip20"),
+                &PortfolioLimits {
+                    min_withdraw: Some(Uint128::new(100)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        WITHDRAW_QUEUES
+            .insert(
+                deps.as_mut().storage,
+                &Addr::unchecked("a_snip20"),
+                &WithdrawQueueConfig {
+                    threshold: Uint128::new(10),
+                    release: QueueRelease::Epoch { length: 3600 },
+                },
+            )
+            .unwrap();
+
+        let err = withdraw(deps.as_mut(), mock_env(), 50, None).unwrap_err();
+        assert!(matches!(err, ContractError::LimitExceeded(_)));
+
+        let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+        config.contract_status = ContractStatus::PROTECTED;
+        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+        let response = withdraw(deps.as_mut(), mock_env(), 50, None).unwrap();
+        assert_eq!(response.messages.len(), 2);
+        assert_eq!(
+            portfolio_msg(&response.messages[0]),
+            PortfolioExecuteMsg::EmergencyWithdraw {
+                share: Uint128::new(10).pow(NORMALIZATION_FACTOR) / Uint128::new(20),
+                receiver: Addr::unchecked("user"),
+            }
+        );
+    }
+
+    #[test]
+    fn keepers_are_rewarded_only_for_swaps() {
+        let mut deps = mock_dependencies();
+        setup(deps.as_mut());
+        let mut config = CONFIG.load(deps.as_ref().storage).unwrap();
+        config.keeper_reward = Some(KeeperReward {
+            token: contract_info("reward"),
+            per_rebalance: Uint128::new(10),
+            epoch_cap: Uint128::new(15),
+            epoch_length: 86_400,
+        });
+        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+        KEEPER_POOL
+            .save(deps.as_mut().storage, &Uint128::new(100))
+            .unwrap();
+        for name in ["a", "b", "c", "d"] {
+            save_portfolio(deps.as_mut(), portfolio(name, vec![position("usdc", 100)]));
+        }
+        let keeper = Addr::unchecked("keeper");
+        UPDATE_BATCH
+            .save(
+                deps.as_mut().storage,
+                &UpdateBatch {
+                    keeper: keeper.clone(),
+                    portfolios: vec![
+                        Addr::unchecked("a_snip20"),
+                        Addr::unchecked("b_snip20"),
+                        Addr::unchecked("c_snip20"),
+                        Addr::unchecked("d_snip20"),
+                    ],
+                },
+            )
+            .unwrap();
+
+        let response = try_finish_update(deps.as_mut(), mock_env(), swaps("a", 0)).unwrap();
+        assert!(response.messages.is_empty());
+        let response = try_finish_update(deps.as_mut(), mock_env(), swaps("b", 2)).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        // The epoch cap leaves 5 for the next rebalance
+        let response = try_finish_update(deps.as_mut(), mock_env(), swaps("c", 1)).unwrap();
+        assert_eq!(response.messages.len(), 1);
+        let response = try_finish_update(deps.as_mut(), mock_env(), None).unwrap();
+        assert!(response.messages.is_empty());
+
+        assert_eq!(
+            KEEPERS.get(deps.as_ref().storage, &keeper),
+            Some(KeeperStats {
+                rebalances: 2,
+                failed: 1,
+                rewards: Uint128::new(15),
+                last_active: mock_env().block.time.seconds(),
+            })
+        );
+        assert_eq!(
+            KEEPER_POOL.load(deps.as_ref().storage).unwrap(),
+            Uint128::new(85)
+        );
+        assert!(LAST_UPDATED
+            .get(deps.as_ref().storage, &Addr::unchecked("d_snip20"))
+            .is_none());
+
+        // Only the admin funds the pool, in the reward token
+        let err = try_fund_keeper_rewards(
+            deps.as_mut(),
+            mock_info("reward", &[]),
+            Addr::unchecked("user"),
+            Uint256::from(50u128),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized(_)));
+        try_fund_keeper_rewards(
+            deps.as_mut(),
+            mock_info("reward", &[]),
+            Addr::unchecked("admin"),
+            Uint256::from(50u128),
+        )
+        .unwrap();
+        assert_eq!(
+            KEEPER_POOL.load(deps.as_ref().storage).unwrap(),
+            Uint128::new(135)
+        );
+    }
+
+    #[test]
+    fn swaps_are_counted_from_the_portfolio_events() {
+        let contract = Addr::unchecked("a_contract");
+        let mut events = swaps("a", 2).unwrap();
+        // A nested portfolio's swaps and other events don't count
+        events.extend(swaps("b", 3).unwrap());
+        events.push(Event::new("transfer").add_attribute_plaintext("rebalance_swaps", "5"));
+        assert_eq!(count_swaps(&events, &contract), 2);
+        assert_eq!(count_swaps(&[], &contract), 0);
+    }
+
+    #[test]
+    fn swaps_are_estimated_by_pairing_drift() {
+        // 30 over target fills the first shortfall and part of the second
+        let weights = vec![weight(30, true), weight(20, false), weight(20, false)];
+        assert_eq!(estimate_swaps(&weights, 1), 3);
+    }
+}
@@ -0,0 +1,82 @@
+use cosmwasm_std::{ConversionOverflowError, DivideByZeroError, OverflowError, StdError};
+use thiserror::Error;
+
+// Every message starts with a stable code clients can match on, codes are unique and never
+// reused for a different meaning, the portfolio contract has its own P codes
+#[derive(Error, Debug, PartialEq)]
+pub enum ContractError {
+    #[error("F00 {0}")]
+    Std(#[from] StdError),
+    #[error("F01 Math error: {0}")]
+    Overflow(#[from] OverflowError),
+    #[error("F02 Math error: {0}")]
+    DivideByZero(#[from] DivideByZeroError),
+    #[error("F03 Math error: {0}")]
+    ConversionOverflow(#[from] ConversionOverflowError),
+    #[error("F10 Unauthorized: must be {0}")]
+    Unauthorized(String),
+    #[error("F11 Deposits are disabled")]
+    DepositsDisabled,
+    #[error("F12 Contract is frozen")]
+    Frozen,
+    #[error("F13 Contract is protected, only emergency withdrawals are open")]
+    Protected,
+    #[error("F20 Invalid weights: {0}")]
+    InvalidWeights(String),
+    #[error("F21 Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("F22 Invalid token: {0}")]
+    InvalidToken(String),
+    #[error("F23 Invalid amount: {0}")]
+    InvalidAmount(String),
+    #[error("F30 Stale price: no current price for {0}")]
+    StalePrice(String),
+    #[error("F31 Route not found: {0}")]
+    RouteNotFound(String),
+    #[error("F40 Not found: {0}")]
+    NotFound(String),
+    #[error("F50 Invalid viewing key")]
+    InvalidViewingKey,
+    #[error("F51 Query requires a permit")]
+    PermitRequired,
+    #[error("F52 Access denied: {0}")]
+    AccessDenied(String),
+    #[error("F60 Limit exceeded: {0}")]
+    LimitExceeded(String),
+    #[error("F61 Assets held for users cannot be rescued")]
+    RescueForbidden,
+    #[error("F80 Unknown reply id {0}")]
+    UnknownReply(u64),
+    #[error("F81 Message not recognized")]
+    UnknownMessage,
+}
+
+impl ContractError {
+    // The code the message starts with
+    pub fn code(&self) -> &'static str {
+        match self {
+            ContractError::Std(_) => "F00",
+            ContractError::Overflow(_) => "F01",
+            ContractError::DivideByZero(_) => "F02",
+            ContractError::ConversionOverflow(_) => "F03",
+            ContractError::Unauthorized(_) => "F10",
+            ContractError::DepositsDisabled => "F11",
+            ContractError::Frozen => "F12",
+            ContractError::Protected => "F13",
+            ContractError::InvalidWeights(_) => "F20",
+            ContractError::InvalidConfig(_) => "F21",
+            ContractError::InvalidToken(_) => "F22",
+            ContractError::InvalidAmount(_) => "F23",
+            ContractError::StalePrice(_) => "F30",
+            ContractError::RouteNotFound(_) => "F31",
+            ContractError::NotFound(_) => "F40",
+            ContractError::InvalidViewingKey => "F50",
+            ContractError::PermitRequired => "F51",
+            ContractError::AccessDenied(_) => "F52",
+            ContractError::LimitExceeded(_) => "F60",
+            ContractError::RescueForbidden => "F61",
+            ContractError::UnknownReply(_) => "F80",
+            ContractError::UnknownMessage => "F81",
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use cosmwasm_std::{Addr, Event, Response};
+
+use crate::state::AddressPrivacy;
+
+// Event attributes are plaintext so indexers can read them without decoding set_data,
+// account and portfolio addresses follow the contract's AddressPrivacy setting
+
+pub fn add_address_attribute(
+    response: Response,
+    key: &str,
+    address: &Addr,
+    privacy: &AddressPrivacy,
+) -> Response {
+    match privacy {
+        AddressPrivacy::Plaintext => response.add_attribute_plaintext(key, address),
+        AddressPrivacy::Encrypted => response.add_attribute(key, address),
+        AddressPrivacy::Omitted => response,
+    }
+}
+
+pub fn add_event_address(
+    event: Event,
+    key: &str,
+    address: &Addr,
+    privacy: &AddressPrivacy,
+) -> Event {
+    match privacy {
+        AddressPrivacy::Plaintext => event.add_attribute_plaintext(key, address),
+        AddressPrivacy::Encrypted => event.add_attribute(key, address),
+        AddressPrivacy::Omitted => event,
+    }
+}
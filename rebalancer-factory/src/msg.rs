@@ -1,7 +1,12 @@
-use crate::state::{ContractStatus, PortfolioConfig, RouteKey, SwapContract};
+use crate::state::{
+    AddressPrivacy, ContractStatus, DcaPlan, KeeperReward, KeeperStats, LimitBounds, Portfolio,
+    PortfolioAccess, PortfolioConfig, PortfolioLimits, QueuedWithdraw, RouteKey, SwapContract,
+    UpdatePriority, WithdrawQueueConfig,
+};
 use cosmwasm_schema::cw_serde;
 use cosmwasm_std::{
-    to_binary, Addr, Binary, ContractInfo, DepsMut, StdError, StdResult, Uint128, Uint256,
+    to_binary, Addr, Binary, ContractInfo, CosmosMsg, Decimal256, QuerierWrapper, StdError,
+    StdResult, Uint128, Uint256, WasmMsg,
 };
 
 #[cw_serde]
@@ -14,6 +19,14 @@ pub struct InstantiateMsg {
     pub snip20_code_id: i32,
     pub portfolio_code_id: i32,
     pub accepted_deposit_tokens: Option<Vec<ContractInfo>>,
+    pub address_privacy: Option<AddressPrivacy>,
+    pub limit_bounds: Option<LimitBounds>,
+    pub tolerance_percent: Option<u128>,
+    pub keeper_reward: Option<KeeperReward>,
+    pub update_priority: Option<UpdatePriority>,
+    pub rebalance_interval: Option<u64>,
+    pub price_feeder: Option<Addr>,
+    pub max_price_age: Option<u64>,
 }
 
 #[cw_serde]
@@ -27,15 +40,108 @@ pub enum ExecuteMsg {
         snip20_code_id: Option<i32>,
         accepted_deposit_tokens: Option<Vec<ContractInfo>>,
         contract_status: Option<ContractStatus>,
+        address_privacy: Option<AddressPrivacy>,
+        limit_bounds: Option<LimitBounds>,
+        tolerance_percent: Option<u128>,
+        keeper_reward: Option<KeeperReward>,
+        update_priority: Option<UpdatePriority>,
+        rebalance_interval: Option<u64>,
+        price_feeder: Option<Addr>,
+        max_price_age: Option<u64>,
     },
+    // Registered assets can be priced with SetPrices
     RegisterAssets {
         assets: Vec<ContractInfo>,
     },
-    // Responsible for rebalancing protfolios
-    // Will reset UNUPDATED_LIST if found empty
+    // Admin or price feeder, each price is 18 decimal USD for one whole token
+    SetPrices {
+        prices: Vec<SetPrice>,
+    },
+    // The portfolio and its share token must already be instantiated with this factory and
+    // its viewing key, deposit tokens default to all accepted deposit tokens
+    RegisterPortfolio {
+        portfolio: Portfolio,
+        deposit_tokens: Option<Vec<Addr>>,
+    },
+    // Caches the swap route between two assets, None removes it
+    SetRoute {
+        key: RouteKey,
+        route: Option<Vec<SwapContract>>,
+    },
+    // Sent by a portfolio when a weight change takes effect, nesting is checked again
+    SetPortfolioWeights {
+        portfolio_snip20: Addr,
+        config: Vec<PortfolioConfig>,
+    },
+    // Sets a viewing key so the stray token's balance can be listed
+    RegisterStrayToken {
+        token: ContractInfo,
+    },
+    // Sends a registered stray token to recipient, the whole balance when amount is not set
+    RescueTokens {
+        token: Addr,
+        amount: Option<Uint128>,
+        recipient: Addr,
+    },
+    // Responsible for rebalancing protfolios, anyone can call and earn the keeper reward
+    // Will reset UNUPDATED_LIST if found empty, reads the first 20 portfolios of the list
     Update {
         batch_amount: Option<Uint128>,
     },
+    // Mints shares for the sender's staged basket
+    DepositBasket {
+        portfolio_snip20: Addr,
+        min_shares: Option<Uint128>,
+    },
+    CancelBasket {
+        portfolio_snip20: Addr,
+    },
+    // Deposits due DCA tranches, up to limit plans out of the next 100 checked, anyone can call
+    ExecuteDca {
+        limit: Option<u32>,
+    },
+    // Refunds the tranches not yet deposited
+    CancelDca {
+        plan_id: u64,
+    },
+    SetViewingKey {
+        key: String,
+    },
+    // Pays out released queued withdrawals, up to limit requests out of the next 100 checked,
+    // anyone can call, requests whose payout fails stay queued
+    ProcessWithdraws {
+        limit: Option<u32>,
+    },
+    // Sent by ProcessWithdraws to the factory itself, one per released request
+    ReleaseWithdraw {
+        request_id: u64,
+    },
+    // Returns the escrowed shares
+    CancelWithdraw {
+        request_id: u64,
+    },
+    // CREATOR
+    // permit_queries makes the portfolio's balance and NAV queries require a permit
+    SetPortfolioAccess {
+        portfolio_snip20: Addr,
+        access: PortfolioAccess,
+        permit_queries: bool,
+    },
+    UpdateAllowlist {
+        portfolio_snip20: Addr,
+        add: Vec<Addr>,
+        remove: Vec<Addr>,
+    },
+    // Must be within the factory's limit bounds
+    SetPortfolioLimits {
+        portfolio_snip20: Addr,
+        limits: PortfolioLimits,
+    },
+    // Withdrawals are paid out at once when queue is not set
+    SetWithdrawQueue {
+        portfolio_snip20: Addr,
+        queue: Option<WithdrawQueueConfig>,
+    },
 
     //Receiver interface
     Receive {
@@ -52,11 +158,37 @@ pub enum ReceiveMsg {
     CreatePortfolio {
         config: Vec<PortfolioConfig>,
         name: String,
+        // Subset of the factory's accepted deposit tokens, all of them if not set
+        #[serde(default)]
+        deposit_tokens: Option<Vec<Addr>>,
     },
-    Withdraw {},
+    // Pays out a slice of every asset, or a single asset when zap_out is set
+    Withdraw {
+        #[serde(default)]
+        zap_out: Option<ZapOut>,
+    },
+    // gate_key is the depositor's viewing key for the gate token of token-gated portfolios
     Deposit {
         portfolio_snip20: Addr,
+        #[serde(default)]
+        gate_key: Option<String>,
     },
+    // In-kind deposit, send each portfolio asset then call DepositBasket
+    StageBasket {
+        portfolio_snip20: Addr,
+        #[serde(default)]
+        gate_key: Option<String>,
+    },
+    // Splits the sent deposit token into tranches, the first is due at once
+    CreateDcaPlan {
+        portfolio_snip20: Addr,
+        tranches: u32,
+        interval: u64,
+        #[serde(default)]
+        gate_key: Option<String>,
+    },
+    // Adds to the keeper pool, must be the reward token sent by the admin
+    FundKeeperRewards {},
 }
 
 #[cw_serde]
@@ -65,14 +197,91 @@ pub enum QueryMsg {
     GetConfig {},
     GetState {},
     GetUnupdated {},
-    Prices { assets: Vec<Addr>, key: String },
-    Route { route: RouteKey, key: String },
+    Prices {
+        assets: Vec<Addr>,
+        key: String,
+    },
+    Route {
+        route: RouteKey,
+        key: String,
+    },
+    Nav {
+        portfolio_snip20: Addr,
+    },
+    DepositTokens {
+        page: u32,
+        page_size: u32,
+    },
+    // Token supplies used for market cap weighting
+    Supplies {
+        assets: Vec<ContractInfo>,
+    },
+    DcaPlans {
+        address: Addr,
+        key: String,
+        page: u32,
+        page_size: u32,
+    },
+    PortfolioAccess {
+        portfolio_snip20: Addr,
+    },
+    // The user's remaining capacity needs address and their viewing key
+    Capacity {
+        portfolio_snip20: Addr,
+        address: Option<Addr>,
+        key: Option<String>,
+    },
+    WithdrawQueue {
+        portfolio_snip20: Addr,
+    },
+    StrayTokens {},
+    QueuedWithdraws {
+        address: Addr,
+        key: String,
+        page: u32,
+        page_size: u32,
+    },
+    // Used by portfolios to check permit signers, key is the factory viewing key
+    IsAllowed {
+        portfolio_snip20: Addr,
+        address: Addr,
+        key: String,
+    },
+    Keepers {
+        page: u32,
+        page_size: u32,
+    },
+    // Used by portfolios before queueing or applying new weights, error is None when valid
+    CheckNesting {
+        portfolio_snip20: Addr,
+        config: Vec<PortfolioConfig>,
+    },
+    // Checks page_size portfolios from the list, at most 100, and returns the ones the crank
+    // would update
+    NeedsRebalance {
+        page: u32,
+        page_size: u32,
+    },
 }
 
+// 18 decimal USD for one whole token, decimals is the token's own
 #[cw_serde]
 pub struct Price {
     pub asset: Addr,
     pub price: Uint128,
+    pub decimals: u8,
+}
+
+#[cw_serde]
+pub struct SetPrice {
+    pub asset: Addr,
+    pub price: Uint128,
+}
+
+#[cw_serde]
+pub struct Supply {
+    pub asset: Addr,
+    pub total_supply: Uint128,
 }
 
 #[cw_serde]
@@ -80,24 +289,235 @@ pub struct Route {
     pub key: RouteKey,
     pub route: Vec<SwapContract>,
     pub router_contract: ContractInfo,
+    #[serde(default)]
+    pub kind: RouteKind,
+}
+
+// How a route is executed, portfolio shares go through the factory instead of a router
+#[cw_serde]
+#[derive(Default)]
+pub enum RouteKind {
+    #[default]
+    Swap,
+    // Deposit into the portfolio whose share is the output
+    Deposit,
+    // Withdraw from the portfolio whose share is the input, zapped out to the output
+    Withdraw,
+}
+
+// Values are 18 decimal USD
+#[cw_serde]
+pub struct Nav {
+    pub total_value: Uint256,
+    pub total_supply: Uint128,
+    // Value of one whole share token
+    pub nav_per_share: Uint256,
+}
+
+#[cw_serde]
+pub struct TokenBalance {
+    pub token: Addr,
+    pub amount: Uint128,
+}
+
+#[cw_serde]
+pub struct Capacity {
+    pub limits: PortfolioLimits,
+    // Value that can still be deposited in 18 decimal USD, none when uncapped
+    pub tvl_remaining: Option<Uint256>,
+    pub user_remaining: Option<Uint256>,
+}
+
+#[cw_serde]
+pub struct WeightItem {
+    pub asset: Addr,
+    pub balance: Uint128,
+    pub price: Uint128,
+    pub value: Uint256,
+    pub current_weight: Decimal256,
+    pub target_weight: Decimal256,
+    // Absolute distance from target, over_target gives the direction
+    pub drift: Decimal256,
+    pub over_target: bool,
+    pub rebalance_due: bool,
+}
+
+#[cw_serde]
+pub struct RebalanceCandidate {
+    pub portfolio_snip20: Addr,
+    // Largest single asset drift from target
+    pub drift: Decimal256,
+    pub last_updated: u64,
+    pub interval_passed: bool,
+    // One swap per over and under target pair Update matches, plus queued deposit swaps
+    pub estimated_swaps: u32,
+}
+
+#[cw_serde]
+pub struct KeeperInfo {
+    pub keeper: Addr,
+    pub stats: KeeperStats,
+}
+
+#[cw_serde]
+pub struct PortfolioDepositTokens {
+    pub portfolio_snip20: Addr,
+    pub deposit_tokens: Vec<ContractInfo>,
 }
 
 #[cw_serde]
 pub enum QueryAnswer {
-    Prices { prices: Vec<Price> },
-    Route { route: Route },
+    Prices {
+        prices: Vec<Price>,
+    },
+    Route {
+        route: Route,
+    },
+    Supplies {
+        supplies: Vec<Supply>,
+    },
+    Nav {
+        nav: Nav,
+    },
+    DepositTokens {
+        portfolios: Vec<PortfolioDepositTokens>,
+        total: u32,
+    },
+    DcaPlans {
+        plans: Vec<DcaPlan>,
+        total: u32,
+    },
+    PortfolioAccess {
+        access: PortfolioAccess,
+        permit_queries: bool,
+    },
+    IsAllowed {
+        allowed: bool,
+    },
+    CheckNesting {
+        error: Option<String>,
+    },
+    Capacity {
+        capacity: Capacity,
+    },
+    WithdrawQueue {
+        queue: Option<WithdrawQueueConfig>,
+        total_shares: Uint128,
+    },
+    QueuedWithdraws {
+        requests: Vec<QueuedWithdraw>,
+        total: u32,
+    },
+    StrayTokens {
+        tokens: Vec<TokenBalance>,
+    },
+    Keepers {
+        keepers: Vec<KeeperInfo>,
+        total: u32,
+        reward: Option<KeeperReward>,
+        pool: Uint128,
+        // Paid so far in the current epoch
+        epoch_paid: Uint128,
+    },
+    NeedsRebalance {
+        portfolios: Vec<RebalanceCandidate>,
+        // Portfolios in the list, checked or not
+        total: u32,
+    },
+}
+
+// Swaps every withdrawn slice into one asset through factory routes
+#[cw_serde]
+pub struct ZapOut {
+    pub asset: ContractInfo,
+    pub min_output: Uint128,
+}
+
+// Portfolio contract messages sent by the factory
+#[cw_serde]
+pub enum PortfolioExecuteMsg {
+    Withdraw {
+        share: Uint128,
+        receiver: Addr,
+        fee: u128,
+        zap_out: Option<ZapOut>,
+    },
+    DepositBasket {
+        depositor: Addr,
+        min_shares: Option<Uint128>,
+    },
+    CancelBasket {
+        depositor: Addr,
+    },
+    // Raw pro-rata payout with no prices or routes, sent while the factory is PROTECTED
+    EmergencyWithdraw {
+        share: Uint128,
+        receiver: Addr,
+    },
+    SetPermitQueries {
+        enabled: bool,
+    },
+    SetLimits {
+        limits: PortfolioLimits,
+    },
+    Update {
+        tolerance_percent: u128,
+    },
+}
+
+#[cw_serde]
+pub enum PortfolioReceiveMsg {
+    Deposit { depositor: Addr },
+    StageBasket { depositor: Addr },
+}
+
+// Portfolio contract queries used by the factory
+#[cw_serde]
+pub enum PortfolioQueryMsg {
+    // key is the portfolio's viewing key, needed when its queries require a permit
+    GetNav {
+        #[serde(default)]
+        key: Option<String>,
+    },
+    // key is the portfolio's viewing key, needed with address
+    GetCapacity {
+        address: Option<Addr>,
+        key: Option<String>,
+    },
+    // key is the portfolio's viewing key, needed when its queries require a permit
+    GetWeights {
+        tolerance_percent: Option<u128>,
+        key: Option<String>,
+    },
+}
+
+#[cw_serde]
+pub enum PortfolioQueryAnswer {
+    GetNav {
+        nav: Nav,
+    },
+    GetCapacity {
+        capacity: Capacity,
+    },
+    GetWeights {
+        weights: Vec<WeightItem>,
+        total_value: Uint256,
+        rebalance_due: bool,
+        #[serde(default)]
+        deposit_swaps: u32,
+    },
 }
 
 #[cw_serde]
 pub enum ExecuteResponse {}
 
 pub fn query_prices(
-    deps: &DepsMut,
+    querier: &QuerierWrapper,
     contract: ContractInfo,
     assets: Vec<Addr>,
     key: String,
 ) -> Result<Vec<Price>, StdError> {
-    match deps.querier.query(&cosmwasm_std::QueryRequest::Wasm(
+    match querier.query(&cosmwasm_std::QueryRequest::Wasm(
         cosmwasm_std::WasmQuery::Smart {
             contract_addr: contract.address.into_string(),
             code_hash: contract.code_hash,
@@ -109,13 +529,30 @@ pub fn query_prices(
     }
 }
 
+pub fn query_supplies(
+    querier: &QuerierWrapper,
+    contract: ContractInfo,
+    assets: Vec<ContractInfo>,
+) -> Result<Vec<Supply>, StdError> {
+    match querier.query(&cosmwasm_std::QueryRequest::Wasm(
+        cosmwasm_std::WasmQuery::Smart {
+            contract_addr: contract.address.into_string(),
+            code_hash: contract.code_hash,
+            msg: to_binary(&QueryMsg::Supplies { assets })?,
+        },
+    ))? {
+        QueryAnswer::Supplies { supplies } => Ok(supplies),
+        _ => Err(StdError::generic_err("Query supplies error")),
+    }
+}
+
 pub fn query_route(
-    deps: &DepsMut,
+    querier: &QuerierWrapper,
     contract: ContractInfo,
     route: RouteKey,
     key: String,
 ) -> Result<Route, StdError> {
-    match deps.querier.query(&cosmwasm_std::QueryRequest::Wasm(
+    match querier.query(&cosmwasm_std::QueryRequest::Wasm(
         cosmwasm_std::WasmQuery::Smart {
             contract_addr: contract.address.into_string(),
             code_hash: contract.code_hash,
@@ -126,3 +563,131 @@ pub fn query_route(
         _ => Err(StdError::generic_err("Query route error")),
     }
 }
+
+pub fn query_portfolio_nav(
+    querier: &QuerierWrapper,
+    contract: ContractInfo,
+    key: Option<String>,
+) -> StdResult<Nav> {
+    match querier.query(&cosmwasm_std::QueryRequest::Wasm(
+        cosmwasm_std::WasmQuery::Smart {
+            contract_addr: contract.address.into_string(),
+            code_hash: contract.code_hash,
+            msg: to_binary(&PortfolioQueryMsg::GetNav { key })?,
+        },
+    ))? {
+        PortfolioQueryAnswer::GetNav { nav } => Ok(nav),
+        _ => Err(StdError::generic_err("Query portfolio nav error")),
+    }
+}
+
+pub fn query_portfolio_capacity(
+    querier: &QuerierWrapper,
+    contract: ContractInfo,
+    address: Option<Addr>,
+    key: Option<String>,
+) -> StdResult<Capacity> {
+    match querier.query(&cosmwasm_std::QueryRequest::Wasm(
+        cosmwasm_std::WasmQuery::Smart {
+            contract_addr: contract.address.into_string(),
+            code_hash: contract.code_hash,
+            msg: to_binary(&PortfolioQueryMsg::GetCapacity { address, key })?,
+        },
+    ))? {
+        PortfolioQueryAnswer::GetCapacity { capacity } => Ok(capacity),
+        _ => Err(StdError::generic_err("Query portfolio capacity error")),
+    }
+}
+
+// Per asset weights and whether any asset is outside its tolerance
+// Weights, whether any is out of tolerance, and the swaps queued deposits need
+pub fn query_portfolio_weights(
+    querier: &QuerierWrapper,
+    contract: ContractInfo,
+    tolerance_percent: Option<u128>,
+    key: Option<String>,
+) -> StdResult<(Vec<WeightItem>, bool, u32)> {
+    match querier.query(&cosmwasm_std::QueryRequest::Wasm(
+        cosmwasm_std::WasmQuery::Smart {
+            contract_addr: contract.address.into_string(),
+            code_hash: contract.code_hash,
+            msg: to_binary(&PortfolioQueryMsg::GetWeights {
+                tolerance_percent,
+                key,
+            })?,
+        },
+    ))? {
+        PortfolioQueryAnswer::GetWeights {
+            weights,
+            rebalance_due,
+            deposit_swaps,
+            ..
+        } => Ok((weights, rebalance_due, deposit_swaps)),
+        _ => Err(StdError::generic_err("Query portfolio weights error")),
+    }
+}
+
+pub fn query_is_allowed(
+    querier: &QuerierWrapper,
+    contract: ContractInfo,
+    portfolio_snip20: Addr,
+    address: Addr,
+    key: String,
+) -> StdResult<bool> {
+    match querier.query(&cosmwasm_std::QueryRequest::Wasm(
+        cosmwasm_std::WasmQuery::Smart {
+            contract_addr: contract.address.into_string(),
+            code_hash: contract.code_hash,
+            msg: to_binary(&QueryMsg::IsAllowed {
+                portfolio_snip20,
+                address,
+                key,
+            })?,
+        },
+    ))? {
+        QueryAnswer::IsAllowed { allowed } => Ok(allowed),
+        _ => Err(StdError::generic_err("Query is allowed error")),
+    }
+}
+
+pub fn query_check_nesting(
+    querier: &QuerierWrapper,
+    contract: ContractInfo,
+    portfolio_snip20: Addr,
+    config: Vec<PortfolioConfig>,
+) -> StdResult<Option<String>> {
+    match querier.query(&cosmwasm_std::QueryRequest::Wasm(
+        cosmwasm_std::WasmQuery::Smart {
+            contract_addr: contract.address.into_string(),
+            code_hash: contract.code_hash,
+            msg: to_binary(&QueryMsg::CheckNesting {
+                portfolio_snip20,
+                config,
+            })?,
+        },
+    ))? {
+        QueryAnswer::CheckNesting { error } => Ok(error),
+        _ => Err(StdError::generic_err("Query check nesting error")),
+    }
+}
+
+pub fn factory_execute_msg(contract: ContractInfo, msg: &ExecuteMsg) -> StdResult<CosmosMsg> {
+    Ok(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: contract.address.into_string(),
+        code_hash: contract.code_hash,
+        msg: to_binary(msg)?,
+        funds: vec![],
+    }))
+}
+
+pub fn portfolio_execute_msg(
+    contract: ContractInfo,
+    msg: &PortfolioExecuteMsg,
+) -> StdResult<CosmosMsg> {
+    Ok(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: contract.address.into_string(),
+        code_hash: contract.code_hash,
+        msg: to_binary(msg)?,
+        funds: vec![],
+    }))
+}
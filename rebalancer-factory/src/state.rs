@@ -1,5 +1,7 @@
+use crate::contract::DEFAULT_MAX_PRICE_AGE;
+use crate::msg::ZapOut;
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, ContractInfo, Storage, Uint128};
+use cosmwasm_std::{Addr, ContractInfo, Storage, Uint128, Uint256};
 use secret_toolkit::storage::Item;
 use secret_toolkit::{serialization::Json, storage::Keymap};
 
@@ -12,6 +14,16 @@ pub enum ContractStatus {
     PROTECTED, // withdraw functionality only
 }
 
+// How account and portfolio addresses appear in emitted events
+#[cw_serde]
+#[derive(Default)]
+pub enum AddressPrivacy {
+    #[default]
+    Plaintext,
+    Encrypted,
+    Omitted,
+}
+
 #[cw_serde]
 pub struct Config {
     pub admin: Addr,
@@ -22,12 +34,141 @@ pub struct Config {
     pub portfolio_code_id: i32,
     pub accepted_deposit_tokens: Vec<ContractInfo>,
     pub contract_status: ContractStatus,
+    #[serde(default)]
+    pub address_privacy: AddressPrivacy,
+    #[serde(default)]
+    pub limit_bounds: LimitBounds,
+    // Passed to each portfolio's Update by the crank, portfolios may override it
+    #[serde(default)]
+    pub tolerance_percent: Option<u128>,
+    // No rewards are paid when not set
+    #[serde(default)]
+    pub keeper_reward: Option<KeeperReward>,
+    #[serde(default)]
+    pub update_priority: UpdatePriority,
+    // Seconds after which a portfolio is updated even within tolerance
+    #[serde(default)]
+    pub rebalance_interval: Option<u64>,
+    // May set prices besides the admin
+    #[serde(default)]
+    pub price_feeder: Option<Addr>,
+    // Seconds a set price is used for before assets priced by it are stale
+    #[serde(default = "default_max_price_age")]
+    pub max_price_age: u64,
+}
+
+fn default_max_price_age() -> u64 {
+    DEFAULT_MAX_PRICE_AGE
+}
+
+// 18 decimal USD for one whole token, from SetPrices
+#[cw_serde]
+pub struct AssetPrice {
+    pub price: Uint128,
+    pub updated: u64,
+}
+
+// Which pending portfolios the crank updates first
+#[cw_serde]
+#[derive(Default)]
+pub enum UpdatePriority {
+    // Largest single asset drift from target
+    #[default]
+    Drift,
+    // Longest since the last successful update
+    Staleness,
+}
+
+// Paid to whoever cranks Update for each portfolio it rebalances, out of the keeper pool
+// Unlike a pool fed straight from protocol fees, withdraw fees are paid to the admin in
+// whatever assets were withdrawn, so the pool is only what the admin sends with
+// FundKeeperRewards in the reward token, sends from anyone else are rejected
+#[cw_serde]
+pub struct KeeperReward {
+    pub token: ContractInfo,
+    pub per_rebalance: Uint128,
+    // Most paid to all keepers in one epoch
+    pub epoch_cap: Uint128,
+    // Seconds, epochs are counted from time zero
+    pub epoch_length: u64,
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub struct KeeperStats {
+    pub rebalances: u64,
+    pub failed: u64,
+    pub rewards: Uint128,
+    pub last_active: u64,
+}
+
+#[cw_serde]
+pub struct RewardEpoch {
+    pub epoch: u64,
+    pub paid: Uint128,
+}
+
+// Portfolios sent Update by the running crank, each is removed as its reply arrives
+#[cw_serde]
+pub struct UpdateBatch {
+    pub keeper: Addr,
+    pub portfolios: Vec<Addr>,
+}
+
+// Deposit and withdraw limits set by a portfolio's creator, none when not set
+// Values are 18 decimal USD, the unit of total_value, whatever the decimals of the asset
+#[cw_serde]
+#[derive(Default)]
+pub struct PortfolioLimits {
+    pub tvl_cap: Option<Uint256>,
+    // Net value deposited by one address
+    pub user_cap: Option<Uint256>,
+    pub min_deposit: Option<Uint256>,
+    // In share tokens
+    pub min_withdraw: Option<Uint128>,
+}
+
+// Highest limits a creator may set, unbounded when not set, in the units of PortfolioLimits
+#[cw_serde]
+#[derive(Default)]
+pub struct LimitBounds {
+    pub max_tvl_cap: Option<Uint256>,
+    pub max_user_cap: Option<Uint256>,
+    pub max_min_deposit: Option<Uint256>,
+    pub max_min_withdraw: Option<Uint128>,
 }
 
 #[cw_serde]
 pub struct PortfolioConfig {
     pub percent: u32,
     pub asset: ContractInfo,
+    #[serde(default)]
+    pub tolerance: Option<ToleranceBand>,
+}
+
+// Drift allowed for a single asset before it is rebalanced
+// The tighter of the two bands applies
+#[cw_serde]
+pub struct ToleranceBand {
+    // Percentage points of total portfolio value
+    pub absolute_percent: u128,
+    // Percent of the asset's target value
+    pub relative_percent: u128,
+}
+
+// Who may deposit into a portfolio, its creator always can
+#[cw_serde]
+#[derive(Default)]
+pub enum PortfolioAccess {
+    #[default]
+    Public,
+    // Addresses on the portfolio's allowlist
+    Whitelist,
+    // Allowlisted addresses and holders of at least min_balance of token
+    TokenGated {
+        token: ContractInfo,
+        min_balance: Uint128,
+    },
 }
 
 #[cw_serde]
@@ -36,6 +177,56 @@ pub struct Portfolio {
     pub creator: Addr,
     pub name: String,
     pub snip20: ContractInfo,
+    pub contract: ContractInfo,
+}
+
+// Deposit token held by the factory and paid into a portfolio one tranche per interval
+#[cw_serde]
+pub struct DcaPlan {
+    pub id: u64,
+    pub owner: Addr,
+    pub portfolio_snip20: Addr,
+    pub deposit_token: ContractInfo,
+    pub tranche_amount: Uint128,
+    pub remaining_tranches: u32,
+    // The last tranche pays out whatever is left
+    pub remaining_amount: Uint128,
+    // Seconds between tranches
+    pub interval: u64,
+    pub next_time: u64,
+}
+
+// Withdrawals above threshold shares are escrowed here and paid out once released
+#[cw_serde]
+pub struct WithdrawQueueConfig {
+    pub threshold: Uint128,
+    pub release: QueueRelease,
+}
+
+#[cw_serde]
+pub enum QueueRelease {
+    // Seconds after the request
+    Cooldown { seconds: u64 },
+    // At the start of the next epoch, epochs are length seconds from time zero
+    Epoch { length: u64 },
+}
+
+// Windows are as long as the queue's cooldown or epoch, counted from time zero
+#[cw_serde]
+pub struct WithdrawWindow {
+    pub window: u64,
+    pub amount: Uint128,
+}
+
+#[cw_serde]
+pub struct QueuedWithdraw {
+    pub id: u64,
+    pub owner: Addr,
+    pub portfolio_snip20: Addr,
+    // Escrowed share tokens
+    pub amount: Uint128,
+    pub zap_out: Option<ZapOut>,
+    pub ready_at: u64,
 }
 
 #[cw_serde]
@@ -51,16 +242,89 @@ pub const KEY_CONFIG: &[u8] = b"config";
 pub const KEY_PORTFOLIO_LIST: &[u8] = b"portfolio_list";
 pub const KEY_UNUPDATED_LIST: &[u8] = b"unupdated_list";
 pub const KEY_REGISTERED_ASSETS: &[u8] = b"registered_assets";
+pub const KEY_ASSET_DECIMALS: &[u8] = b"asset_decimals";
+pub const KEY_ASSET_PRICES: &[u8] = b"asset_prices";
 pub const KEY_VIEWING_KEY: &[u8] = b"viewing_key";
 pub const KEY_PORTFOLIO: &[u8] = b"portfolio";
 pub const KEY_ROUTE_CACHE: &[u8] = b"route_cache";
+pub const KEY_DEPOSIT_TOKENS: &[u8] = b"deposit_tokens";
+pub const KEY_PORTFOLIO_CONTRACTS: &[u8] = b"portfolio_contracts";
+pub const KEY_DCA_PLANS: &[u8] = b"dca_plans";
+pub const KEY_DCA_PLAN_COUNT: &[u8] = b"dca_plan_count";
+pub const KEY_USER_DCA_PLANS: &[u8] = b"user_dca_plans";
+pub const KEY_DCA_CURSOR: &[u8] = b"dca_cursor";
+pub const KEY_DCA_TOKEN_PLANS: &[u8] = b"dca_token_plans";
+pub const KEY_PORTFOLIO_ACCESS: &[u8] = b"portfolio_access";
+pub const KEY_ALLOWLIST: &[u8] = b"allowlist";
+pub const KEY_PERMIT_QUERIES: &[u8] = b"permit_queries";
+pub const KEY_PORTFOLIO_LIMITS: &[u8] = b"portfolio_limits";
+pub const KEY_WITHDRAW_QUEUES: &[u8] = b"withdraw_queues";
+pub const KEY_QUEUED_WITHDRAWS: &[u8] = b"queued_withdraws";
+pub const KEY_QUEUED_WITHDRAW_COUNT: &[u8] = b"queued_withdraw_count";
+pub const KEY_USER_QUEUED_WITHDRAWS: &[u8] = b"user_queued_withdraws";
+pub const KEY_QUEUED_SHARES: &[u8] = b"queued_shares";
+pub const KEY_WITHDRAW_CURSOR: &[u8] = b"withdraw_cursor";
+pub const KEY_WITHDRAW_WINDOWS: &[u8] = b"withdraw_windows";
+pub const KEY_STRAY_TOKENS: &[u8] = b"stray_tokens";
+pub const KEY_KEEPER_POOL: &[u8] = b"keeper_pool";
+pub const KEY_KEEPERS: &[u8] = b"keepers";
+pub const KEY_REWARD_EPOCH: &[u8] = b"reward_epoch";
+pub const KEY_REWARDED_EPOCH: &[u8] = b"rewarded_epoch";
+pub const KEY_UPDATE_BATCH: &[u8] = b"update_batch";
+pub const KEY_LAST_UPDATED: &[u8] = b"last_updated";
 pub static CONFIG: Item<Config> = Item::new(KEY_CONFIG);
 // List of all known portfolios
 pub static PORTFOLIO_LIST: Item<Vec<Addr>> = Item::new(KEY_PORTFOLIO_LIST);
 // List of all portfolios pending update
 pub static UNUPDATED_LIST: Item<Vec<Addr>> = Item::new(KEY_UNUPDATED_LIST);
 pub static REGISTERED_ASSETS: Item<Vec<Addr>> = Item::new(KEY_REGISTERED_ASSETS);
+// Read from each asset's token info when it is registered
+pub static ASSET_DECIMALS: Keymap<Addr, u8> = Keymap::new(KEY_ASSET_DECIMALS);
+pub static ASSET_PRICES: Keymap<Addr, AssetPrice> = Keymap::new(KEY_ASSET_PRICES);
 pub static VIEWING_KEY: Item<String> = Item::new(KEY_VIEWING_KEY);
 // Hash map of snip20 protfolio token and the portfolio information
 pub static PORTFOLIO: Keymap<Addr, Portfolio> = Keymap::new(KEY_PORTFOLIO);
+// Portfolio snip20 keyed by the portfolio contract, to recognise nested parents depositing
+pub static PORTFOLIO_CONTRACTS: Keymap<Addr, Addr> = Keymap::new(KEY_PORTFOLIO_CONTRACTS);
+// Deposit tokens chosen by each portfolio's creator, keyed by portfolio snip20
+pub static DEPOSIT_TOKENS: Keymap<Addr, Vec<ContractInfo>> = Keymap::new(KEY_DEPOSIT_TOKENS);
+pub static DCA_PLANS: Keymap<u64, DcaPlan> = Keymap::new(KEY_DCA_PLANS);
+pub static DCA_PLAN_COUNT: Item<u64> = Item::new(KEY_DCA_PLAN_COUNT);
+// Plan id ExecuteDca resumes checking from, wraps around at DCA_PLAN_COUNT
+pub static DCA_CURSOR: Item<u64> = Item::new(KEY_DCA_CURSOR);
+// Open plans escrowing each deposit token, those tokens can't be rescued
+pub static DCA_TOKEN_PLANS: Keymap<Addr, u64> = Keymap::new(KEY_DCA_TOKEN_PLANS);
+// Active plan ids, suffixed by owner
+pub static USER_DCA_PLANS: Keymap<u64, bool> = Keymap::new(KEY_USER_DCA_PLANS);
+// Public when not set, keyed by portfolio snip20
+pub static PORTFOLIO_ACCESS: Keymap<Addr, PortfolioAccess> = Keymap::new(KEY_PORTFOLIO_ACCESS);
+// Approved depositors, suffixed by portfolio snip20
+pub static ALLOWLIST: Keymap<Addr, bool> = Keymap::new(KEY_ALLOWLIST);
+// Portfolios whose balance and NAV queries need a permit, keyed by portfolio snip20
+pub static PERMIT_QUERIES: Keymap<Addr, bool> = Keymap::new(KEY_PERMIT_QUERIES);
+// Keyed by portfolio snip20, mirrored on the portfolio contract
+pub static PORTFOLIO_LIMITS: Keymap<Addr, PortfolioLimits> = Keymap::new(KEY_PORTFOLIO_LIMITS);
+// Portfolios without an entry pay out every withdrawal at once
+pub static WITHDRAW_QUEUES: Keymap<Addr, WithdrawQueueConfig> = Keymap::new(KEY_WITHDRAW_QUEUES);
+pub static QUEUED_WITHDRAWS: Keymap<u64, QueuedWithdraw> = Keymap::new(KEY_QUEUED_WITHDRAWS);
+pub static QUEUED_WITHDRAW_COUNT: Item<u64> = Item::new(KEY_QUEUED_WITHDRAW_COUNT);
+// Pending request ids, suffixed by owner
+pub static USER_QUEUED_WITHDRAWS: Keymap<u64, bool> = Keymap::new(KEY_USER_QUEUED_WITHDRAWS);
+// Escrowed shares, keyed by portfolio snip20
+pub static QUEUED_SHARES: Keymap<Addr, Uint128> = Keymap::new(KEY_QUEUED_SHARES);
+// Request id ProcessWithdraws resumes checking from, wraps around at QUEUED_WITHDRAW_COUNT
+pub static WITHDRAW_CURSOR: Item<u64> = Item::new(KEY_WITHDRAW_CURSOR);
+// Shares each owner withdrew without queueing in the current window, suffixed by portfolio snip20
+pub static WITHDRAW_WINDOWS: Keymap<Addr, WithdrawWindow> = Keymap::new(KEY_WITHDRAW_WINDOWS);
+// Tokens sent here by mistake, registered so their balance can be read and rescued
+pub static STRAY_TOKENS: Keymap<Addr, ContractInfo> = Keymap::new(KEY_STRAY_TOKENS);
+// Protocol fees set aside for keeper rewards, in the reward token
+pub static KEEPER_POOL: Item<Uint128> = Item::new(KEY_KEEPER_POOL);
+pub static KEEPERS: Keymap<Addr, KeeperStats> = Keymap::new(KEY_KEEPERS);
+pub static REWARD_EPOCH: Item<RewardEpoch> = Item::new(KEY_REWARD_EPOCH);
+// Last epoch each portfolio earned its keeper a reward, keyed by portfolio snip20
+pub static REWARDED_EPOCH: Keymap<Addr, u64> = Keymap::new(KEY_REWARDED_EPOCH);
+pub static UPDATE_BATCH: Item<UpdateBatch> = Item::new(KEY_UPDATE_BATCH);
+// Time of each portfolio's last successful update, keyed by portfolio snip20
+pub static LAST_UPDATED: Keymap<Addr, u64> = Keymap::new(KEY_LAST_UPDATED);
 pub static ROUTE_CACHE: Keymap<RouteKey, Vec<SwapContract>> = Keymap::new(KEY_ROUTE_CACHE);